@@ -0,0 +1,283 @@
+use log::{error, warn};
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_LIMIT: usize = 100;
+pub const DEFAULT_REPLAY: usize = 20;
+/// How often buffered messages are written to the history file.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Chat line kept for scrollback, `time` is in milliseconds since the epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    #[serde(default)]
+    pub id: u64,
+    pub room: String,
+    pub from: String,
+    pub text: String,
+    pub time: u64,
+}
+
+impl HistoryEntry {
+    pub fn new(id: u64, room: &str, from: &str, text: &str) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or_default();
+
+        HistoryEntry {
+            id,
+            room: room.to_owned(),
+            from: from.to_owned(),
+            text: text.to_owned(),
+            time,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        format!("{}: {}", self.from, self.text)
+    }
+}
+
+/// Last `limit` messages of every room, including rooms that were emptied and dropped, so
+/// joining them again replays what was said. With a `path`, messages are buffered and appended to
+/// that file as JSON lines on `flush`, and loaded back on start. The file is rewritten with
+/// only what is kept once it holds twice as many lines.
+pub struct History {
+    limit: usize,
+    replay: usize,
+    path: Option<PathBuf>,
+    rooms: HashMap<String, VecDeque<HistoryEntry>>,
+    /// Messages not written yet.
+    pending: Vec<HistoryEntry>,
+    /// Lines in the file.
+    lines: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_LIMIT, DEFAULT_REPLAY, None)
+    }
+}
+
+impl History {
+    pub fn new(limit: usize, replay: usize, path: Option<PathBuf>) -> Self {
+        let mut history = History {
+            limit,
+            replay,
+            path,
+            rooms: HashMap::new(),
+            pending: Vec::new(),
+            lines: 0,
+        };
+
+        history.load();
+        history
+    }
+
+    /// `CHAT_HISTORY_LIMIT`, `CHAT_HISTORY_REPLAY` and `CHAT_HISTORY_PATH`.
+    pub fn from_env() -> Self {
+        let limit = env_usize("CHAT_HISTORY_LIMIT", DEFAULT_LIMIT);
+        let replay = env_usize("CHAT_HISTORY_REPLAY", DEFAULT_REPLAY);
+        let path = env::var("CHAT_HISTORY_PATH").ok().map(PathBuf::from);
+
+        History::new(limit, replay, path)
+    }
+
+    /// Number of messages replayed to new members.
+    pub fn get_replay(&self) -> usize {
+        self.replay
+    }
+
+    /// Highest message id kept, ids of new messages continue from there.
+    pub fn get_last_id(&self) -> u64 {
+        self.rooms.values().flatten().map(|entry| entry.id).max().unwrap_or_default()
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        if self.path.is_some() {
+            self.pending.push(entry.clone());
+        }
+
+        self.insert(entry);
+    }
+
+    /// Appends buffered messages to the file in one write, or compacts it instead when it
+    /// grew to twice what is kept.
+    pub fn flush(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let kept: usize = self.rooms.values().map(VecDeque::len).sum();
+        if self.lines + self.pending.len() > 2 * kept.max(self.limit) {
+            self.pending.clear();
+            self.save();
+            return;
+        }
+
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let mut text = String::new();
+        for entry in self.pending.drain(..) {
+            if let Ok(line) = serde_json::to_string(&entry) {
+                text.push_str(&line);
+                text.push('\n');
+                self.lines += 1;
+            }
+        }
+
+        let appended = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(text.as_bytes()));
+
+        if let Err(e) = appended {
+            error!("Cannot append to history {}: {}", path.display(), e);
+        }
+    }
+
+    /// Last `n` messages of `room_name`, oldest first.
+    pub fn last(&self, room_name: &str, n: usize) -> Vec<HistoryEntry> {
+        match self.rooms.get(room_name) {
+            Some(entries) => entries.iter().skip(entries.len().saturating_sub(n)).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, entry: HistoryEntry) {
+        let entries = self.rooms.entry(entry.room.clone()).or_default();
+
+        entries.push_back(entry);
+        while entries.len() > self.limit {
+            entries.pop_front();
+        }
+    }
+
+    /// Loads the file and compacts it to what is kept in memory.
+    fn load(&mut self) {
+        let file = match &self.path {
+            Some(path) if path.exists() => match File::open(path) {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Cannot open history {}: {}", path.display(), e);
+                    return;
+                }
+            },
+            _ => return,
+        };
+
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            match serde_json::from_str(&line) {
+                Ok(entry) => self.insert(entry),
+                Err(e) => warn!("Skip history line {:?}: {}", line, e),
+            }
+        }
+
+        self.save();
+    }
+
+    fn save(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut entries: Vec<&HistoryEntry> = self.rooms.values().flatten().collect();
+        entries.sort_by_key(|entry| entry.id);
+
+        let mut text = String::new();
+        let mut lines = 0;
+        for entry in entries {
+            if let Ok(line) = serde_json::to_string(entry) {
+                text.push_str(&line);
+                text.push('\n');
+                lines += 1;
+            }
+        }
+
+        if let Err(e) = fs::write(path, text) {
+            error!("Cannot write history {}: {}", path.display(), e);
+        }
+
+        self.lines = lines;
+    }
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("Invalid {} {:?}, using {}", name, value, default);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use crate::history::{History, HistoryEntry};
+
+    #[test]
+    fn test_trim() {
+        let mut history = History::new(2, 2, None);
+        for id in 1..=3 {
+            history.push(HistoryEntry::new(id, "rust", "alice", &id.to_string()));
+        }
+        history.push(HistoryEntry::new(4, "main", "bob", "hi"));
+
+        let ids: Vec<u64> = history.last("rust", 10).iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(history.last("rust", 1)[0].id, 3);
+        assert_eq!(history.get_last_id(), 4);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = env::temp_dir().join(format!("chat-history-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let lines = || fs::read_to_string(&path).map(|text| text.lines().count()).unwrap_or_default();
+
+        let mut history = History::new(2, 2, Some(path.clone()));
+        history.push(HistoryEntry::new(1, "rust", "alice", "hi"));
+        history.push(HistoryEntry::new(2, "main", "bob", "hello"));
+        assert_eq!(lines(), 0);
+
+        history.flush();
+        assert_eq!(lines(), 2);
+
+        let mut history = History::new(2, 2, Some(path.clone()));
+        assert_eq!(history.last("rust", 10)[0].text, "hi");
+        assert_eq!(history.get_last_id(), 2);
+
+        // Appends until the file holds twice what is kept, then rewrites it with what is kept
+        for id in 3..=6 {
+            history.push(HistoryEntry::new(id, "rust", "alice", &id.to_string()));
+            history.flush();
+        }
+        assert_eq!(lines(), 6);
+
+        history.push(HistoryEntry::new(7, "rust", "alice", "7"));
+        history.flush();
+        assert_eq!(lines(), 3);
+
+        let history = History::new(2, 2, Some(path.clone()));
+        let ids: Vec<u64> = history.last("rust", 10).iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![6, 7]);
+        assert_eq!(history.last("main", 10)[0].text, "hello");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,33 +1,46 @@
 use log::info;
 
 use actix_files::Files;
+use actix_web::http::header::SEC_WEBSOCKET_PROTOCOL;
 use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
-use std::{
-    sync::{Arc, Mutex},
-};
 
 #[macro_use]
 pub extern crate serde_derive;
 #[macro_use]
 extern crate lazy_static;
 
-mod event;
+mod history;
 mod message;
+mod outbox;
 mod server;
 mod session;
 
+use outbox::Transport;
 use session::WsChatSession;
-use event::EventEmitter;
 
 async fn chat_route(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
-    ws::start(WsChatSession::default(), &req, stream)
+    let protocols = req.headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocols| protocols.to_str().ok())
+        .unwrap_or_default();
+
+    let session = WsChatSession::new(protocols, req.peer_addr().map(|addr| addr.ip()));
+    let outbox = session.get_outbox();
+
+    let mut res = ws::handshake_with_protocols(&req, &[message::JSON_PROTOCOL])?;
+    let (addr, frames) = ws::WebsocketContext::create_with_addr(session, stream);
+
+    Ok(res.streaming(Transport::new(frames, outbox, addr.recipient())))
 }
 
-#[actix_rt::main]
-async fn main() -> std::io::Result<()> {
+/// Outbox counters of all chat sessions, for monitoring.
+async fn outbox_stats() -> HttpResponse {
+    HttpResponse::Ok().json(outbox::STATS.snapshot())
+}
 
-    let event_emitter =EventEmitter::new();
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
 
     env_logger::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
@@ -36,6 +49,7 @@ async fn main() -> std::io::Result<()> {
     let srv = HttpServer::new(move || {
         App::new()
             .service(web::resource("/ws/").to(chat_route))
+            .service(web::resource("/stats/outbox").to(outbox_stats))
             .service(Files::new("/", "./static/").index_file("index.html"))
     })
     .bind(&addr)?;
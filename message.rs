@@ -1,61 +1,625 @@
 use actix::prelude::*;
 
+use std::net::IpAddr;
+
+use crate::history::HistoryEntry;
+use crate::outbox::Client;
+
 pub extern crate serde;
 pub extern crate serde_json;
 
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// WebSocket subprotocol a client asks for at the handshake to speak JSON, sessions without
+/// it use the terminal client's plain text format.
+pub const JSON_PROTOCOL: &str = "chat.json.v1";
+
+/// JSON request, e.g. `{"v":1,"id":"7","cmd":"join","room":"rust"}`. `v` is optional and
+/// checked by `parse_request`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Request {
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// Settings of a room, given when it is created.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RoomOptions {
+    #[serde(default)]
+    pub private: bool,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub invites: Vec<String>,
+    pub max_members: Option<usize>,
+}
+
+/// Member addressed by a moderation command, by nickname or by member id.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Name(String),
+    Id(usize),
+}
+
+impl Target {
+    pub fn parse(target: &str) -> Self {
+        match target.parse::<usize>() {
+            Ok(id) => Target::Id(id),
+            Err(_) => Target::Name(target.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    Create {
+        room: String,
+        #[serde(flatten)]
+        options: RoomOptions,
+    },
+    Join { room: String, password: Option<String> },
+    /// Parts the active room.
+    Leave,
+    /// Parts `room`, or the active room.
+    Part { room: Option<String> },
+    /// Makes an already joined room the active one.
+    Switch { room: String },
+    List,
+    /// Last `limit` messages of `room`, or the active room.
+    History { room: Option<String>, limit: Option<usize> },
+    Rename { name: String },
+    /// Sends to `room`, or the active room.
+    Send { room: Option<String>, text: String },
+    /// Tells `room`, or the active room, that the session is typing. Repeat while typing,
+    /// it is not answered.
+    Typing { room: Option<String> },
+    /// Marks messages up to `message` as read.
+    Read { room: Option<String>, message: u64 },
+    /// Current names of the members that read `message`.
+    Seen { room: Option<String>, message: u64 },
+    /// Private message to whoever holds the name `to`, wherever they are.
+    Whisper { to: String, text: String },
+    /// Refuses whispers from the session holding `name`, under any later name too, until
+    /// unblocked or either session disconnects.
+    Block { name: String },
+    Unblock { name: String },
+    Op { name: String },
+    Deop { name: String },
+    Kick {
+        #[serde(flatten)]
+        target: Target,
+        reason: Option<String>,
+    },
+    Ban {
+        #[serde(flatten)]
+        target: Target,
+        minutes: Option<u64>,
+        reason: Option<String>,
+    },
+    Unban { name: String },
+    Mute {
+        #[serde(flatten)]
+        target: Target,
+        minutes: Option<u64>,
+    },
+    Unmute { name: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    UnsupportedVersion,
+    UnknownCommand,
+    MissingArgument,
+    RoomExists,
+    RoomPrivate,
+    WrongPassword,
+    RoomFull,
+    NotFound,
+    NotOperator,
+    Banned,
+    Muted,
+    InvalidName,
+    NameTaken,
+    Offline,
+    Blocked,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        ProtocolError {
+            code,
+            message: message.to_owned(),
+        }
+    }
+}
+
+pub const MIN_NAME_LEN: usize = 2;
+pub const MAX_NAME_LEN: usize = 24;
+
+/// Nicknames start with a letter and continue with letters, digits, `_` or `-`.
+pub fn validate_name(name: &str) -> Result<(), ProtocolError> {
+    let len = name.chars().count();
+    if !(MIN_NAME_LEN..=MAX_NAME_LEN).contains(&len) {
+        let message = format!("name must be {} to {} characters long", MIN_NAME_LEN, MAX_NAME_LEN);
+        return Err(ProtocolError::new(ErrorCode::InvalidName, &message));
+    }
+
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        let message = "name must start with a letter and contain only letters, digits, '_' or '-'";
+        return Err(ProtocolError::new(ErrorCode::InvalidName, message));
+    }
+
+    if name.eq_ignore_ascii_case("anon") {
+        return Err(ProtocolError::new(ErrorCode::InvalidName, "name anon is reserved"));
+    }
+
+    Ok(())
+}
+
+/// Parses a slash command or a plain chat line from the terminal client into the same
+/// commands the JSON protocol uses.
+pub fn parse_command(text: &str) -> Result<Command, ProtocolError> {
+    let text = text.trim();
+
+    if !text.starts_with('/') {
+        return Ok(Command::Send { room: None, text: text.to_owned() });
+    }
+
+    let mut command = text.splitn(2, ' ');
+    let name = command.next();
+    let arg = command.next().map(str::trim).filter(|arg| !arg.is_empty());
+
+    match (name, arg) {
+        (Some("/list"), _) => Ok(Command::List),
+        (Some("/leave"), _) => Ok(Command::Leave),
+        (Some("/part"), room) => Ok(Command::Part { room: room.map(room_name) }),
+        (Some("/history"), args) => {
+            let (mut room, mut limit) = (None, None);
+            for arg in args.unwrap_or_default().split_whitespace() {
+                match arg.parse::<usize>() {
+                    Ok(n) => limit = Some(n),
+                    Err(_) => room = Some(room_name(arg)),
+                }
+            }
+            Ok(Command::History { room, limit })
+        }
+        (Some("/whisper"), Some(args)) | (Some("/w"), Some(args)) => {
+            let mut args = args.splitn(2, ' ');
+            let to = args.next().unwrap_or_default().to_owned();
+            match args.next().map(str::trim).filter(|text| !text.is_empty()) {
+                Some(text) => Ok(Command::Whisper { to, text: text.to_owned() }),
+                None => Err(ProtocolError::new(ErrorCode::MissingArgument, "message text is required")),
+            }
+        }
+        (Some("/block"), Some(name)) => Ok(Command::Block { name: name.to_owned() }),
+        (Some("/unblock"), Some(name)) => Ok(Command::Unblock { name: name.to_owned() }),
+        (Some("/whisper"), None) | (Some("/w"), None) | (Some("/block"), None) | (Some("/unblock"), None) => {
+            Err(ProtocolError::new(ErrorCode::MissingArgument, "name is required"))
+        }
+        (Some("/typing"), room) => Ok(Command::Typing { room: room.map(room_name) }),
+        (Some(command @ "/read"), Some(args)) | (Some(command @ "/seen"), Some(args)) => {
+            let mut args = args.split_whitespace();
+            let message = match args.next().map(|id| id.trim_start_matches('#').parse::<u64>()) {
+                Some(Ok(message)) => message,
+                _ => return Err(ProtocolError::new(ErrorCode::BadRequest, "message id must be a number")),
+            };
+            let room = args.next().map(room_name);
+
+            if command == "/read" {
+                Ok(Command::Read { room, message })
+            } else {
+                Ok(Command::Seen { room, message })
+            }
+        }
+        (Some("/read"), None) | (Some("/seen"), None) => Err(ProtocolError::new(ErrorCode::MissingArgument, "message id is required")),
+        (Some("/switch"), Some(room)) => Ok(Command::Switch { room: room_name(room) }),
+        (Some("/msg"), Some(args)) => {
+            let mut args = args.splitn(2, ' ');
+            let room = room_name(args.next().unwrap_or_default());
+            match args.next().map(str::trim).filter(|text| !text.is_empty()) {
+                Some(text) => Ok(Command::Send { room: Some(room), text: text.to_owned() }),
+                None => Err(ProtocolError::new(ErrorCode::MissingArgument, "message text is required")),
+            }
+        }
+        (Some("/create"), Some(args)) => parse_create(args),
+        (Some("/create"), None) => Err(ProtocolError::new(ErrorCode::MissingArgument, "room name is required")),
+        (Some("/join"), Some(args)) => {
+            let mut args = args.split_whitespace();
+            let room = room_name(args.next().unwrap_or_default());
+            Ok(Command::Join { room, password: args.next().map(str::to_owned) })
+        }
+        (Some("/join"), None) => Err(ProtocolError::new(ErrorCode::MissingArgument, "room name is required")),
+        (Some("/name"), Some(name)) => Ok(Command::Rename { name: name.to_owned() }),
+        (Some("/name"), None) => Err(ProtocolError::new(ErrorCode::MissingArgument, "name is required")),
+        (Some("/op"), Some(name)) => Ok(Command::Op { name: name.to_owned() }),
+        (Some("/deop"), Some(name)) => Ok(Command::Deop { name: name.to_owned() }),
+        (Some("/unban"), Some(name)) => Ok(Command::Unban { name: name.to_owned() }),
+        (Some("/unmute"), Some(name)) => Ok(Command::Unmute { name: name.to_owned() }),
+        (Some("/kick"), Some(args)) => {
+            let mut args = args.splitn(2, ' ');
+            let target = Target::parse(args.next().unwrap_or_default());
+            let reason = args.next().map(str::trim).filter(|reason| !reason.is_empty());
+            Ok(Command::Kick { target, reason: reason.map(str::to_owned) })
+        }
+        (Some("/ban"), Some(args)) => {
+            let (target, minutes, reason) = parse_sanction(args);
+            Ok(Command::Ban { target, minutes, reason })
+        }
+        (Some("/mute"), Some(args)) => {
+            let (target, minutes, _) = parse_sanction(args);
+            Ok(Command::Mute { target, minutes })
+        }
+        (Some("/switch"), None) | (Some("/msg"), None) => Err(ProtocolError::new(ErrorCode::MissingArgument, "room name is required")),
+        (Some(command), None) if ["/op", "/deop", "/kick", "/ban", "/unban", "/mute", "/unmute"].contains(&command) => {
+            Err(ProtocolError::new(ErrorCode::MissingArgument, "target is required"))
+        }
+        _ => Err(ProtocolError::new(ErrorCode::UnknownCommand, &format!("unknown command: {:?}", text))),
+    }
+}
+
+/// Room names may be written as `#room`.
+fn room_name(room: &str) -> String {
+    room.trim_start_matches('#').to_owned()
+}
+
+/// `<target> [minutes] [reason]`
+fn parse_sanction(args: &str) -> (Target, Option<u64>, Option<String>) {
+    let mut args = args.splitn(2, ' ');
+    let target = Target::parse(args.next().unwrap_or_default());
+    let rest = args.next().map(str::trim).unwrap_or_default();
+
+    let mut rest_args = rest.splitn(2, ' ');
+    match rest_args.next().and_then(|minutes| minutes.parse::<u64>().ok()) {
+        Some(minutes) => (target, Some(minutes), rest_args.next().map(str::to_owned)),
+        None if rest.is_empty() => (target, None, None),
+        None => (target, None, Some(rest.to_owned())),
+    }
+}
+
+/// `/create <room> [private] [password=<secret>] [invite=<name>,<name>] [max=<members>]`
+fn parse_create(args: &str) -> Result<Command, ProtocolError> {
+    let mut args = args.split_whitespace();
+    let room = args.next().unwrap_or_default().to_owned();
+    let mut options = RoomOptions::default();
+
+    for arg in args {
+        let mut option = arg.splitn(2, '=');
+
+        match (option.next(), option.next()) {
+            (Some("private"), None) => options.private = true,
+            (Some("password"), Some(password)) if !password.is_empty() => options.password = Some(password.to_owned()),
+            (Some("invite"), Some(names)) => {
+                options.invites.extend(names.split(',').filter(|name| !name.is_empty()).map(str::to_owned))
+            }
+            (Some("max"), Some(max)) => match max.parse::<usize>() {
+                Ok(max) if max > 0 => options.max_members = Some(max),
+                _ => return Err(ProtocolError::new(ErrorCode::BadRequest, &format!("invalid member limit: {:?}", max))),
+            },
+            _ => return Err(ProtocolError::new(ErrorCode::BadRequest, &format!("unknown room option: {:?}", arg))),
+        }
+    }
+
+    Ok(Command::Create { room, options })
+}
+
+/// Parses a JSON request, rejecting versions this server does not speak. Errors carry the
+/// request id whenever the request has one, even if its command cannot be decoded.
+pub fn parse_request(text: &str) -> Result<Request, (Option<String>, ProtocolError)> {
+    let value: serde_json::Value = serde_json::from_str(text)
+        .map_err(|e| (None, ProtocolError::new(ErrorCode::BadRequest, &e.to_string())))?;
+    let id = value.get("id").and_then(|id| id.as_str()).map(str::to_owned);
+
+    if let Some(v) = value.get("v") {
+        if v.as_u64() != Some(PROTOCOL_VERSION as u64) {
+            let message = format!("protocol version {} is not supported", v);
+            return Err((id, ProtocolError::new(ErrorCode::UnsupportedVersion, &message)));
+        }
+    }
+
+    serde_json::from_value(value).map_err(|e| {
+        let message = e.to_string();
+        let code = if message.starts_with("unknown variant") {
+            ErrorCode::UnknownCommand
+        } else {
+            ErrorCode::BadRequest
+        };
+
+        (id, ProtocolError::new(code, &message))
+    })
+}
+
+/// Result of a successful command.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Reply {
+    Created { room: String },
+    Joined { room: String },
+    Left { room: String },
+    Switched { room: String },
+    Rooms { rooms: Vec<String> },
+    History { room: String, messages: Vec<HistoryEntry> },
+    Renamed { name: String },
+    Sent { room: String, message: u64 },
+    Read { room: String, message: u64 },
+    Whispered { to: String },
+    Blocked { name: String },
+    Unblocked { name: String },
+    Seen { room: String, message: u64, by: Vec<String> },
+    Moderated { room: String },
+}
+
+impl Reply {
+    /// Lines the terminal client expects for this reply.
+    pub fn to_lines(&self) -> Vec<String> {
+        match self {
+            Reply::Rooms { rooms } => rooms.clone(),
+            Reply::History { messages, .. } => messages.iter().map(HistoryEntry::to_text).collect(),
+            Reply::Renamed { name } => vec![format!("name changed to: {}", name)],
+            Reply::Seen { message, by, .. } => vec![format!("#{} seen by: {}", message, by.join(", "))],
+            _ => vec![],
+        }
+    }
+}
+
+/// Something that happened in a room the session is in.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChatEvent {
+    Message { id: u64, room: String, from: String, text: String },
+    Typing { room: String, name: String },
+    /// Private message, not tied to a room.
+    Whisper { from: String, text: String },
+    /// Sent once a member stopped sending typing notices for a while.
+    StoppedTyping { room: String, name: String },
+    /// `name` read every message of the room up to `message`.
+    Read { room: String, name: String, message: u64 },
+    Joined { room: String, name: String },
+    Renamed { room: String, from: String, to: String },
+    Left { room: String, name: String },
+    /// Recent messages replayed to a new member.
+    History { room: String, messages: Vec<HistoryEntry> },
+    Moderation {
+        room: String,
+        by: String,
+        action: Action,
+        target: String,
+        minutes: Option<u64>,
+        reason: Option<String>,
+    },
+    /// Sent to a member that was kicked or banned from `room`.
+    Removed { room: String, by: String, reason: Option<String> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Op,
+    Deop,
+    Kick,
+    Ban,
+    Unban,
+    Mute,
+    Unmute,
+}
+
+impl Action {
+    fn past_tense(self) -> &'static str {
+        match self {
+            Action::Op => "made an operator",
+            Action::Deop => "removed as operator",
+            Action::Kick => "kicked",
+            Action::Ban => "banned",
+            Action::Unban => "unbanned",
+            Action::Mute => "muted",
+            Action::Unmute => "unmuted",
+        }
+    }
+}
+
+fn with_reason(text: String, reason: &Option<String>) -> String {
+    match reason {
+        Some(reason) => format!("{}: {}", text, reason),
+        None => text,
+    }
+}
+
+impl ChatEvent {
+    pub fn to_text(&self) -> String {
+        match self {
+            ChatEvent::Message { from, text, .. } => format!("{}: {}", from, text),
+            ChatEvent::Whisper { from, text } => format!("{} whispers: {}", from, text),
+            ChatEvent::Typing { room, name } => format!("{} is typing in {}", name, room),
+            ChatEvent::StoppedTyping { room, name } => format!("{} stopped typing in {}", name, room),
+            ChatEvent::Read { name, message, .. } => format!("{} read up to #{}", name, message),
+            ChatEvent::Joined { room, name } => format!("{} joined {}", name, room),
+            ChatEvent::Renamed { from, to, .. } => format!("{} is now known as {}", from, to),
+            ChatEvent::Left { room, name } => format!("{} left {}", name, room),
+            ChatEvent::History { messages, .. } => {
+                messages.iter().map(HistoryEntry::to_text).collect::<Vec<_>>().join("\n")
+            }
+            ChatEvent::Moderation { room, by, action, target, minutes, reason } => {
+                let mut text = format!("*** {} was {} in {} by {}", target, action.past_tense(), room, by);
+                if let Some(minutes) = minutes {
+                    text.push_str(&format!(" for {} minutes", minutes));
+                }
+                with_reason(text, reason)
+            }
+            ChatEvent::Removed { room, by, reason } => with_reason(format!("!!! you were removed from {} by {}", room, by), reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Ok { v: u32, id: Option<String>, reply: Reply },
+    Error { v: u32, id: Option<String>, code: ErrorCode, message: String },
+    Event { v: u32, event: ChatEvent },
+}
+
+/// Event queued for a session through its `Client`.
+#[derive(Clone)]
+pub struct ChatMessage(pub ChatEvent);
+
+/// Room, session id of the owner and options.
 #[derive(Clone, Message)]
-#[rtype(result = "()")]
-pub struct ChatMessage(pub String);
+#[rtype(result = "Result<(), ProtocolError>")]
+pub struct CreateRoom(pub String, pub usize, pub RoomOptions);
 
+/// Room, session id, password and recipient.
 #[derive(Clone, Message)]
-#[rtype(result = "usize")]
-pub struct JoinRoom(pub String, pub Option<String>, pub Recipient<ChatMessage>);
+#[rtype(result = "Result<usize, ProtocolError>")]
+pub struct JoinRoom(pub String, pub usize, pub Option<String>, pub Client);
 
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
 pub struct LeaveRoom(pub String, pub usize);
 
+/// Rooms visible to the session.
 #[derive(Clone, Message)]
 #[rtype(result = "Vec<String>")]
-pub struct ListRooms;
+pub struct ListRooms(pub usize);
+
+/// Session id, `None` for a new session, requested name, or `None` for a free guest name,
+/// the peer address of the session and the session that whispers to the name go to.
+/// Answers with the session id, which the server gives out on the first claim, and the
+/// name the session now owns.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(usize, String), ProtocolError>")]
+pub struct ClaimName(pub Option<usize>, pub Option<String>, pub Option<IpAddr>, pub Client);
+
+/// Sender session id, recipient name and text.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), ProtocolError>")]
+pub struct Whisper(pub usize, pub String, pub String);
+
+/// Session id, the name of the session to block and whether to block or unblock it.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), ProtocolError>")]
+pub struct SetBlocked(pub usize, pub String, pub bool);
 
+/// Session id of a closed session, its name is free again.
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
+pub struct ReleaseName(pub usize);
+
+/// Room, id of the member asking and the number of messages, by default the replay size.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Vec<HistoryEntry>, ProtocolError>")]
+pub struct GetHistory(pub String, pub usize, pub Option<usize>);
+
+/// Room, id of the member issuing it and a moderation command.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), ProtocolError>")]
+pub struct Moderate(pub String, pub usize, pub Command);
+
+/// Room, sender id and text. Answers with the id of the message.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<u64, ProtocolError>")]
 pub struct SendMessage(pub String, pub usize, pub String);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[allow(clippy::large_enum_variant)]
-pub enum KucoinWebsocketMsg {
-    TickerMsg(WSResp<DefaultMsg>),
-    MarginTradeDoneMsg(WSResp<MarginTradeUpdate>),
-    Error(String),
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct WSResp<T> {
-    pub r#type: String,
-    pub topic: String,
-    pub subject: String,
-    pub data: T,
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct DefaultMsg {
-    pub id: String,
-    pub r#type: String,
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct MarginTradeUpdate {
-    pub currency: String,
-    pub order_id: String,
-    pub daily_int_rate: f32,
-    pub term: i32,
-    pub size: i32,
-    pub lent_size: f32,
-    pub side: String,
-    pub ts: i64,
+/// Room and id of the typing member.
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct Typing(pub String, pub usize);
+
+/// Room, id of the reader and the last message read.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), ProtocolError>")]
+pub struct MarkRead(pub String, pub usize, pub u64);
+
+/// Room, id of the member asking and a message. Answers with the names that read it.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Vec<String>, ProtocolError>")]
+pub struct GetReceipts(pub String, pub usize, pub u64);
+
+#[cfg(test)]
+mod test {
+    use crate::message::{parse_command, parse_request, validate_name, Command, ErrorCode, RoomOptions, Target};
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("alice").is_ok());
+        assert!(validate_name("bob_2-x").is_ok());
+
+        for name in &["a", "1alice", "al ice", "alice!", "Anon", "abcdefghijklmnopqrstuvwxy"] {
+            assert_eq!(validate_name(name).unwrap_err().code, ErrorCode::InvalidName, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(" hello ").unwrap(), Command::Send { room: None, text: "hello".to_owned() });
+        assert_eq!(parse_command("/join #rust secret").unwrap(), Command::Join {
+            room: "rust".to_owned(),
+            password: Some("secret".to_owned()),
+        });
+        assert_eq!(parse_command("/msg rust hi there").unwrap(), Command::Send {
+            room: Some("rust".to_owned()),
+            text: "hi there".to_owned(),
+        });
+        assert_eq!(parse_command("/history rust 5").unwrap(), Command::History {
+            room: Some("rust".to_owned()),
+            limit: Some(5),
+        });
+        assert_eq!(parse_command("/ban 3 10 spam").unwrap(), Command::Ban {
+            target: Target::Id(3),
+            minutes: Some(10),
+            reason: Some("spam".to_owned()),
+        });
+        assert_eq!(parse_command("/create rust private max=5").unwrap(), Command::Create {
+            room: "rust".to_owned(),
+            options: RoomOptions { private: true, max_members: Some(5), ..RoomOptions::default() },
+        });
+    }
+
+    #[test]
+    fn test_parse_command_errors() {
+        assert_eq!(parse_command("/join").unwrap_err().code, ErrorCode::MissingArgument);
+        assert_eq!(parse_command("/read abc").unwrap_err().code, ErrorCode::BadRequest);
+        assert_eq!(parse_command("/create rust max=0").unwrap_err().code, ErrorCode::BadRequest);
+        assert_eq!(parse_command("/dance").unwrap_err().code, ErrorCode::UnknownCommand);
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(r#"{"id":"7","cmd":"join","room":"rust"}"#).unwrap();
+        assert_eq!(request.id.as_deref(), Some("7"));
+        assert_eq!(request.command, Command::Join { room: "rust".to_owned(), password: None });
+
+        let (id, error) = parse_request(r#"{"v":2,"id":"8","cmd":"list"}"#).unwrap_err();
+        assert_eq!(id.as_deref(), Some("8"));
+        assert_eq!(error.code, ErrorCode::UnsupportedVersion);
+
+        let (_, error) = parse_request("{").unwrap_err();
+        assert_eq!(error.code, ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn test_parse_request_errors_keep_id() {
+        let (id, error) = parse_request(r#"{"id":"9","cmd":"dance"}"#).unwrap_err();
+        assert_eq!(id.as_deref(), Some("9"));
+        assert_eq!(error.code, ErrorCode::UnknownCommand);
+
+        let (id, error) = parse_request(r#"{"id":"10","cmd":"join"}"#).unwrap_err();
+        assert_eq!(id.as_deref(), Some("10"));
+        assert_eq!(error.code, ErrorCode::BadRequest);
+
+        let (id, error) = parse_request(r#"{"id":"11","text":"hi"}"#).unwrap_err();
+        assert_eq!(id.as_deref(), Some("11"));
+        assert_eq!(error.code, ErrorCode::BadRequest);
+    }
 }
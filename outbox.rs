@@ -0,0 +1,352 @@
+use log::warn;
+
+use std::collections::VecDeque;
+use std::env;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll};
+
+use actix::prelude::*;
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures_core::Stream;
+
+use crate::message::{ChatEvent, ChatMessage};
+
+pub const DEFAULT_LIMIT: usize = 256;
+/// Bytes of frames that may wait for the connection before a session stops writing events
+/// and leaves them in its outbox, where the limit applies.
+pub const WRITE_WINDOW: usize = 64 * 1024;
+
+/// What happens to a session whose outbox is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    DropOldest,
+    Disconnect,
+}
+
+/// `CHAT_OUTBOX_LIMIT` and `CHAT_OUTBOX_OVERFLOW` (`drop_oldest` or `disconnect`).
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxSettings {
+    pub limit: usize,
+    pub overflow: Overflow,
+}
+
+impl OutboxSettings {
+    pub fn from_env() -> Self {
+        let limit = match env::var("CHAT_OUTBOX_LIMIT").map(|limit| limit.parse::<usize>()) {
+            Ok(Ok(limit)) if limit > 0 => limit,
+            Ok(_) => {
+                warn!("Invalid CHAT_OUTBOX_LIMIT, using {}", DEFAULT_LIMIT);
+                DEFAULT_LIMIT
+            }
+            Err(_) => DEFAULT_LIMIT,
+        };
+
+        let overflow = match env::var("CHAT_OUTBOX_OVERFLOW").as_deref() {
+            Ok("disconnect") => Overflow::Disconnect,
+            Ok("drop_oldest") | Err(_) => Overflow::DropOldest,
+            Ok(other) => {
+                warn!("Invalid CHAT_OUTBOX_OVERFLOW {:?}, dropping oldest", other);
+                Overflow::DropOldest
+            }
+        };
+
+        OutboxSettings { limit, overflow }
+    }
+}
+
+lazy_static! {
+    static ref SETTINGS: OutboxSettings = OutboxSettings::from_env();
+    pub static ref STATS: OutboxStats = OutboxStats::default();
+}
+
+/// Counters over every session since start.
+#[derive(Default)]
+pub struct OutboxStats {
+    queued: AtomicU64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxStatsSnapshot {
+    pub queued: u64,
+    pub delivered: u64,
+    pub dropped: u64,
+    pub disconnected: u64,
+}
+
+impl OutboxStats {
+    pub fn snapshot(&self) -> OutboxStatsSnapshot {
+        OutboxStatsSnapshot {
+            queued: self.queued.load(Ordering::Relaxed),
+            delivered: self.delivered.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Queue {
+    events: VecDeque<ChatEvent>,
+    settings: OutboxSettings,
+    closed: bool,
+    /// Bytes written to the websocket that the connection did not take yet.
+    unsent: usize,
+}
+
+/// Bounded queue of events waiting for a session, shared between the server that fills it,
+/// the session that writes it to the websocket and the `Transport` that sees what the
+/// connection takes. Events stay queued while the connection is behind by `WRITE_WINDOW`.
+#[derive(Clone)]
+pub struct Outbox {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox::new(*SETTINGS)
+    }
+}
+
+/// Result of queueing an event.
+#[derive(Debug, PartialEq)]
+enum Push {
+    /// Queued, `true` when the session has to be told there is something to drain.
+    Queued(bool),
+    /// Overflowed and closed, the session has to be told to disconnect.
+    Closed,
+    /// Closed earlier.
+    Rejected,
+}
+
+impl Outbox {
+    pub fn new(settings: OutboxSettings) -> Self {
+        Outbox {
+            queue: Arc::new(Mutex::new(Queue {
+                events: VecDeque::new(),
+                settings,
+                closed: false,
+                unsent: 0,
+            })),
+        }
+    }
+
+    fn push(&self, event: ChatEvent) -> Push {
+        let mut queue = self.queue.lock().unwrap();
+
+        if queue.closed {
+            return Push::Rejected;
+        }
+
+        if queue.events.len() >= queue.settings.limit {
+            match queue.settings.overflow {
+                Overflow::DropOldest => {
+                    queue.events.pop_front();
+                    STATS.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Overflow::Disconnect => {
+                    STATS.dropped.fetch_add(queue.events.len() as u64, Ordering::Relaxed);
+                    STATS.disconnected.fetch_add(1, Ordering::Relaxed);
+                    queue.events.clear();
+                    queue.closed = true;
+                    return Push::Closed;
+                }
+            }
+        }
+
+        queue.events.push_back(event);
+        STATS.queued.fetch_add(1, Ordering::Relaxed);
+
+        Push::Queued(queue.events.len() == 1)
+    }
+
+    /// Next event to write, unless the connection is `WRITE_WINDOW` behind.
+    pub fn next(&self) -> Option<ChatEvent> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.unsent >= WRITE_WINDOW {
+            return None;
+        }
+
+        let event = queue.events.pop_front();
+        if event.is_some() {
+            STATS.delivered.fetch_add(1, Ordering::Relaxed);
+        }
+        event
+    }
+
+    /// Whether the session overflowed and has to disconnect.
+    pub fn is_closed(&self) -> bool {
+        self.queue.lock().unwrap().closed
+    }
+
+    /// Counts a frame the session wrote to the websocket.
+    pub fn wrote(&self, bytes: usize) {
+        self.queue.lock().unwrap().unsent += bytes;
+    }
+
+    /// Counts `bytes` the connection took, and tells whether events wait for the room
+    /// that freed up.
+    fn sent(&self, bytes: usize) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        queue.unsent = queue.unsent.saturating_sub(bytes);
+
+        !queue.events.is_empty() && queue.unsent < WRITE_WINDOW
+    }
+}
+
+/// Tells a session its outbox has events to drain.
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct Flush;
+
+/// Session as seen by the server. Events go to its outbox, and the session is only woken
+/// once per batch.
+#[derive(Clone)]
+pub struct Client {
+    outbox: Outbox,
+    wake: Recipient<Flush>,
+}
+
+/// The session is gone or was disconnected for being too slow.
+#[derive(Debug)]
+pub struct Disconnected;
+
+impl Client {
+    pub fn new(outbox: Outbox, wake: Recipient<Flush>) -> Self {
+        Client { outbox, wake }
+    }
+
+    pub fn do_send(&self, msg: ChatMessage) -> Result<(), Disconnected> {
+        match self.outbox.push(msg.0) {
+            Push::Queued(true) if self.wake.connected() => {
+                self.wake.do_send(Flush);
+                Ok(())
+            }
+            Push::Queued(true) => Err(Disconnected),
+            Push::Queued(false) => Ok(()),
+            Push::Closed => {
+                self.wake.do_send(Flush);
+                Err(Disconnected)
+            }
+            Push::Rejected => Err(Disconnected),
+        }
+    }
+}
+
+/// Response body of a session. The connection only pulls frames from it while its own
+/// write buffer has room, what it pulls is counted against the session's outbox.
+pub struct Transport<S> {
+    frames: Pin<Box<S>>,
+    outbox: Outbox,
+    wake: Recipient<Flush>,
+}
+
+impl<S> Transport<S> {
+    pub fn new(frames: S, outbox: Outbox, wake: Recipient<Flush>) -> Self {
+        Transport {
+            frames: Box::pin(frames),
+            outbox,
+            wake,
+        }
+    }
+}
+
+impl<S> Stream for Transport<S>
+where
+    S: Stream<Item = Result<Bytes, Error>>,
+{
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let polled = this.frames.as_mut().poll_next(cx);
+
+        if let Poll::Ready(Some(Ok(bytes))) = &polled {
+            if this.outbox.sent(bytes.len()) {
+                this.wake.do_send(Flush);
+            }
+        }
+
+        polled
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use crate::message::ChatEvent;
+    use crate::outbox::{Outbox, OutboxSettings, Overflow, Push, DEFAULT_LIMIT, WRITE_WINDOW};
+
+    fn event(text: &str) -> ChatEvent {
+        ChatEvent::Whisper { from: "alice".to_owned(), text: text.to_owned() }
+    }
+
+    fn texts(outbox: &Outbox) -> Vec<String> {
+        std::iter::from_fn(|| outbox.next())
+            .map(|event| match event {
+                ChatEvent::Whisper { text, .. } => text,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_drop_oldest_keeps_newest() {
+        let outbox = Outbox::new(OutboxSettings { limit: 2, overflow: Overflow::DropOldest });
+        assert_eq!(outbox.push(event("1")), Push::Queued(true));
+        assert_eq!(outbox.push(event("2")), Push::Queued(false));
+        assert_eq!(outbox.push(event("3")), Push::Queued(false));
+
+        assert_eq!(texts(&outbox), vec!["2", "3"]);
+        assert!(!outbox.is_closed());
+    }
+
+    #[test]
+    fn test_disconnect_rejects_later_pushes() {
+        let outbox = Outbox::new(OutboxSettings { limit: 1, overflow: Overflow::Disconnect });
+        assert_eq!(outbox.push(event("1")), Push::Queued(true));
+        assert_eq!(outbox.push(event("2")), Push::Closed);
+        assert_eq!(outbox.push(event("3")), Push::Rejected);
+
+        assert!(outbox.is_closed());
+        assert!(outbox.next().is_none());
+    }
+
+    #[test]
+    fn test_write_window() {
+        let outbox = Outbox::new(OutboxSettings { limit: 4, overflow: Overflow::DropOldest });
+        outbox.push(event("1"));
+        outbox.push(event("2"));
+
+        // The connection is behind, events stay queued and count against the limit
+        outbox.wrote(WRITE_WINDOW);
+        assert!(outbox.next().is_none());
+        for text in &["3", "4", "5"] {
+            outbox.push(event(text));
+        }
+
+        assert!(outbox.sent(1));
+        assert_eq!(texts(&outbox), vec!["2", "3", "4", "5"]);
+        assert!(!outbox.sent(WRITE_WINDOW));
+    }
+
+    #[test]
+    fn test_settings_from_invalid_env() {
+        env::set_var("CHAT_OUTBOX_LIMIT", "0");
+        env::set_var("CHAT_OUTBOX_OVERFLOW", "explode");
+        let settings = OutboxSettings::from_env();
+        assert_eq!(settings.limit, DEFAULT_LIMIT);
+        assert_eq!(settings.overflow, Overflow::DropOldest);
+
+        env::set_var("CHAT_OUTBOX_LIMIT", "many");
+        assert_eq!(OutboxSettings::from_env().limit, DEFAULT_LIMIT);
+
+        env::remove_var("CHAT_OUTBOX_LIMIT");
+        env::remove_var("CHAT_OUTBOX_OVERFLOW");
+    }
+}
@@ -1,28 +1,233 @@
 use actix::prelude::*;
-use actix_broker::BrokerSubscribe;
 
-use std::collections::HashMap;
-use std::mem;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
-use crate::message::{ChatMessage, JoinRoom, LeaveRoom, ListRooms, SendMessage};
+use crate::history::{History, HistoryEntry, FLUSH_INTERVAL};
+use crate::message::{
+    validate_name, Action, ChatEvent, ChatMessage, ClaimName, Command, CreateRoom, ErrorCode,
+    GetHistory, GetReceipts, JoinRoom, LeaveRoom, ListRooms, MarkRead, Moderate, ProtocolError,
+    ReleaseName, RoomOptions, SendMessage, SetBlocked, Target, Typing, Whisper,
+};
+use crate::outbox::Client;
 
-type Client = Recipient<ChatMessage>;
-type Room = HashMap<usize, Client>;
+/// Typing notices closer than this are not forwarded.
+const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+/// Members stop typing once they sent no notice for this long.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+const TYPING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const SANCTION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+type Room = HashMap<usize, Member>;
+
+/// Session in a room, under the name it had when it joined or last renamed.
+struct Member {
+    session: usize,
+    name: String,
+    client: Client,
+}
+
+/// Ban or mute with the address the sanctioned session had, `until` `None` lasts until
+/// lifted.
+struct Sanction {
+    peer: Option<IpAddr>,
+    until: Option<Instant>,
+}
+
+impl Sanction {
+    fn new(peer: Option<IpAddr>, minutes: Option<u64>) -> Self {
+        Sanction {
+            peer,
+            until: minutes.map(|minutes| Instant::now() + Duration::from_secs(minutes * 60)),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.until.is_none_or(|until| until > Instant::now())
+    }
+}
+
+/// Sanctions by the `name_key` of the sanctioned name. They outlive the session, so
+/// reconnecting under the same name or from the same address does not lift them.
+type Sanctions = HashMap<String, Sanction>;
+
+fn is_sanctioned(sanctions: &Sanctions, name: &str, peer: Option<IpAddr>) -> bool {
+    let key = name_key(name);
+
+    sanctions.iter()
+        .filter(|(_, sanction)| sanction.is_active())
+        .any(|(sanctioned, sanction)| *sanctioned == key || (peer.is_some() && sanction.peer == peer))
+}
+
+/// When the last typing notice of a member was forwarded and received.
+struct Typist {
+    sent: Instant,
+    seen: Instant,
+}
+
+/// Who may see, join and moderate a room, and its typing and read state. Rooms created by
+/// joining them are public and owned by their first member. Ownership and operators are
+/// kept by session id, so they follow renames and end with the session. Bans and mutes are
+/// kept by name and address until they expire or are lifted.
+#[derive(Default)]
+struct RoomInfo {
+    owner: Option<usize>,
+    options: RoomOptions,
+    operators: HashSet<usize>,
+    bans: Sanctions,
+    mutes: Sanctions,
+    /// Typing and read state by member id, names are looked up when they are shown.
+    typing: HashMap<usize, Typist>,
+    /// Last message read by each member.
+    receipts: HashMap<usize, u64>,
+}
+
+impl RoomInfo {
+    fn forget_member(&mut self, id: usize) {
+        self.typing.remove(&id);
+        self.receipts.remove(&id);
+    }
+
+    fn is_operator(&self, session: usize) -> bool {
+        self.owner == Some(session) || self.operators.contains(&session)
+    }
+
+    /// Invites are by name, checked against the name a session has when it joins.
+    fn is_invited(&self, session: usize, name: &str) -> bool {
+        self.owner == Some(session) || self.options.invites.iter().any(|n| n == name)
+    }
+
+    fn is_visible(&self, session: usize, name: &str, room: &Room) -> bool {
+        !self.options.private
+            || self.is_invited(session, name)
+            || room.values().any(|m| m.session == session)
+    }
+
+    fn check_join(&self, room_name: &str, session: usize, name: &str, peer: Option<IpAddr>, password: Option<&str>, members: usize) -> Result<(), ProtocolError> {
+        let invited = self.is_invited(session, name);
+
+        if is_sanctioned(&self.bans, name, peer) {
+            return Err(ProtocolError::new(ErrorCode::Banned, &format!("you are banned from room {}", room_name)));
+        }
+
+        if self.options.private && !invited {
+            return Err(ProtocolError::new(ErrorCode::RoomPrivate, &format!("room {} is private", room_name)));
+        }
+
+        if let Some(expected) = &self.options.password {
+            if !invited && password != Some(expected.as_str()) {
+                return Err(ProtocolError::new(ErrorCode::WrongPassword, &format!("wrong password for room {}", room_name)));
+            }
+        }
+
+        match self.options.max_members {
+            Some(max) if members >= max => Err(ProtocolError::new(ErrorCode::RoomFull, &format!("room {} is full", room_name))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether the member an event comes from receives it too.
+#[derive(Clone, Copy, PartialEq)]
+enum Delivery {
+    Everyone,
+    Others,
+}
+
+impl Delivery {
+    /// Senders, joiners and renamed members already got a reply for their own action.
+    fn of(event: &ChatEvent) -> Self {
+        match event {
+            ChatEvent::Message { .. } | ChatEvent::Joined { .. } | ChatEvent::Renamed { .. } => Delivery::Others,
+            ChatEvent::Typing { .. } | ChatEvent::StoppedTyping { .. } | ChatEvent::Read { .. } => Delivery::Others,
+            _ => Delivery::Everyone,
+        }
+    }
+}
+
+/// Names are unique regardless of case.
+fn name_key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// Session with the name it holds, the address it connected from and the sessions it
+/// refuses whispers from.
+struct User {
+    name: String,
+    peer: Option<IpAddr>,
+    client: Client,
+    blocked: HashSet<usize>,
+}
 
 #[derive(Default)]
 pub struct WsChatServer {
     rooms: HashMap<String, Room>,
+    infos: HashMap<String, RoomInfo>,
+    /// Sessions by the id given out on their first name claim.
+    users: HashMap<usize, User>,
+    /// Session holding each name, by `name_key`.
+    names: HashMap<String, usize>,
+    history: History,
+    last_message_id: u64,
+    last_session_id: usize,
 }
 
 impl WsChatServer {
-    fn take_room(&mut self, room_name: &str) -> Option<Room> {
-        let room = self.rooms.get_mut(room_name)?;
-        let room = mem::replace(room, HashMap::new());
-        Some(room)
+    fn member(&self, room_name: &str, id: usize) -> Result<&Member, ProtocolError> {
+        self.rooms.get(room_name)
+            .and_then(|room| room.get(&id))
+            .ok_or_else(|| ProtocolError::new(ErrorCode::NotFound, &format!("you are not in room {}", room_name)))
+    }
+
+    fn member_name(&self, room_name: &str, id: usize) -> Result<String, ProtocolError> {
+        self.member(room_name, id).map(|member| member.name.clone())
+    }
+
+    fn user_name(&self, session: usize) -> Result<String, ProtocolError> {
+        self.users.get(&session)
+            .map(|user| user.name.clone())
+            .ok_or_else(|| ProtocolError::new(ErrorCode::NotFound, "claim a name first"))
     }
 
-    fn add_client_to_room(&mut self, room_name: &str, id: Option<usize>, client: Client) -> usize {
-        let mut id = id.unwrap_or_else(rand::random::<usize>);
+    fn user_peer(&self, session: usize) -> Option<IpAddr> {
+        self.users.get(&session).and_then(|user| user.peer)
+    }
+
+    fn guest_name(&self) -> String {
+        loop {
+            let name = format!("guest{}", rand::random::<u16>());
+            if !self.names.contains_key(&name_key(&name)) {
+                return name;
+            }
+        }
+    }
+
+    /// Renames the members of `session` and tells the rooms they are in.
+    fn rename_member(&mut self, session: usize, from: &str, to: &str) {
+        let mut renamed = Vec::new();
+
+        for (room_name, room) in self.rooms.iter_mut() {
+            for (id, member) in room.iter_mut() {
+                if member.session == session {
+                    member.name = to.to_owned();
+                    renamed.push((room_name.clone(), *id));
+                }
+            }
+        }
+
+        for (room_name, id) in renamed {
+            let msg = ChatEvent::Renamed {
+                room: room_name.clone(),
+                from: from.to_owned(),
+                to: to.to_owned(),
+            };
+            self.send_chat_message(&room_name, msg, id);
+        }
+    }
+
+    fn add_client_to_room(&mut self, room_name: &str, member: Member) -> usize {
+        let mut id = rand::random::<usize>();
 
         if let Some(room) = self.rooms.get_mut(room_name) {
             loop {
@@ -33,38 +238,256 @@ impl WsChatServer {
                 }
             }
 
-            room.insert(id, client);
+            room.insert(id, member);
             return id;
         }
 
         // Create a new room for the first client
         let mut room: Room = HashMap::new();
 
-        room.insert(id, client);
+        room.insert(id, member);
         self.rooms.insert(room_name.to_owned(), room);
+        self.infos.entry(room_name.to_owned()).or_default();
 
         id
     }
 
-    fn send_chat_message(&mut self, room_name: &str, msg: &str, _src: usize) -> Option<()> {
-        let mut room = self.take_room(room_name)?;
+    /// Session holding `name`.
+    fn session_of(&self, name: &str) -> Result<usize, ProtocolError> {
+        self.names.get(&name_key(name))
+            .copied()
+            .ok_or_else(|| ProtocolError::new(ErrorCode::Offline, &format!("{} is offline", name)))
+    }
+
+    /// Session id, `None` for names no one holds, and name of the member `target` refers to.
+    /// Names may belong to sessions outside the room or to no session, so they can be banned
+    /// before they join.
+    fn resolve_target(&self, room_name: &str, target: Target) -> Result<(Option<usize>, String), ProtocolError> {
+        match target {
+            Target::Name(name) => Ok(self.names.get(&name_key(&name))
+                .and_then(|session| self.users.get(session).map(|user| (Some(*session), user.name.clone())))
+                .unwrap_or((None, name))),
+            Target::Id(id) => self.rooms.get(room_name)
+                .and_then(|room| room.get(&id))
+                .map(|member| (Some(member.session), member.name.clone()))
+                .ok_or_else(|| ProtocolError::new(ErrorCode::NotFound, &format!("no member {} in room {}", id, room_name))),
+        }
+    }
 
-        for (id, client) in room.drain() {
-            if client.do_send(ChatMessage(msg.to_owned())).is_ok() {
-                self.add_client_to_room(room_name, Some(id), client);
+    /// Removes the members of `session` from the room and tells them who removed them.
+    fn remove_members(&mut self, room_name: &str, session: usize, by: &str, reason: &Option<String>) -> usize {
+        let room = match self.rooms.get_mut(room_name) {
+            Some(room) => room,
+            None => return 0,
+        };
+
+        let ids: Vec<usize> = room.iter().filter(|(_, m)| m.session == session).map(|(id, _)| *id).collect();
+        for id in ids.iter() {
+            if let Some(info) = self.infos.get_mut(room_name) {
+                info.forget_member(*id);
+            }
+
+            if let Some(member) = room.remove(id) {
+                let removed = ChatEvent::Removed {
+                    room: room_name.to_owned(),
+                    by: by.to_owned(),
+                    reason: reason.clone(),
+                };
+                let _ = member.client.do_send(ChatMessage(removed));
+            }
+        }
+
+        ids.len()
+    }
+
+    fn moderate(&mut self, room_name: &str, actor: usize, command: Command) -> Result<(), ProtocolError> {
+        let (by_session, by) = {
+            let member = self.member(room_name, actor)?;
+            (member.session, member.name.clone())
+        };
+
+        let (action, target, minutes, reason) = match command {
+            Command::Op { name } => (Action::Op, Target::Name(name), None, None),
+            Command::Deop { name } => (Action::Deop, Target::Name(name), None, None),
+            Command::Kick { target, reason } => (Action::Kick, target, None, reason),
+            Command::Ban { target, minutes, reason } => (Action::Ban, target, minutes, reason),
+            Command::Unban { name } => (Action::Unban, Target::Name(name), None, None),
+            Command::Mute { target, minutes } => (Action::Mute, target, minutes, None),
+            Command::Unmute { name } => (Action::Unmute, Target::Name(name), None, None),
+            _ => return Err(ProtocolError::new(ErrorCode::BadRequest, "not a moderation command")),
+        };
+        let (session, target) = self.resolve_target(room_name, target)?;
+        let peer = session.and_then(|session| self.user_peer(session));
+
+        let info = self.infos.entry(room_name.to_owned()).or_default();
+
+        if !info.is_operator(by_session) {
+            return Err(ProtocolError::new(ErrorCode::NotOperator, &format!("you are not an operator of room {}", room_name)));
+        }
+        if session.is_some() && info.owner == session {
+            return Err(ProtocolError::new(ErrorCode::NotOperator, &format!("{} owns room {}", target, room_name)));
+        }
+
+        // Bans and mutes are kept by name, operators are granted to a session
+        let changed = match (action, session) {
+            (Action::Op, Some(session)) => info.operators.insert(session),
+            (Action::Deop, Some(session)) => info.operators.remove(&session),
+            (Action::Op, None) | (Action::Deop, None) => {
+                return Err(ProtocolError::new(ErrorCode::NotFound, &format!("{} is offline", target)));
             }
+            (Action::Ban, _) => {
+                info.bans.insert(name_key(&target), Sanction::new(peer, minutes));
+                true
+            }
+            (Action::Unban, _) => info.bans.remove(&name_key(&target)).is_some(),
+            (Action::Mute, _) => {
+                info.mutes.insert(name_key(&target), Sanction::new(peer, minutes));
+                true
+            }
+            (Action::Unmute, _) => info.mutes.remove(&name_key(&target)).is_some(),
+            (Action::Kick, _) => true,
+        };
+
+        let removed = match (action, session) {
+            (Action::Kick, Some(session)) | (Action::Ban, Some(session)) => self.remove_members(room_name, session, &by, &reason),
+            _ => 0,
+        };
+
+        if !changed || (action == Action::Kick && removed == 0) {
+            return Err(ProtocolError::new(ErrorCode::NotFound, &format!("{} is not affected in room {}", target, room_name)));
+        }
+
+        let audit = ChatEvent::Moderation {
+            room: room_name.to_owned(),
+            by,
+            action,
+            target,
+            minutes,
+            reason,
+        };
+        self.send_chat_message(room_name, audit, actor);
+
+        Ok(())
+    }
+
+    /// Delivers `msg` to the room, to `src` too if the delivery policy of the event says
+    /// so. Members whose session is gone are removed and announced as left.
+    fn send_chat_message(&mut self, room_name: &str, msg: ChatEvent, src: usize) -> Option<()> {
+        let delivery = Delivery::of(&msg);
+        let room = self.rooms.get_mut(room_name)?;
+
+        let dead: Vec<usize> = room.iter()
+            .filter(|(id, _)| delivery == Delivery::Everyone || **id != src)
+            .filter(|(_, member)| member.client.do_send(ChatMessage(msg.clone())).is_err())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in dead {
+            self.remove_client(room_name, id);
         }
 
         Some(())
     }
+
+    /// Removes a member, tells the others and drops the room once it is empty.
+    fn remove_client(&mut self, room_name: &str, id: usize) {
+        let member = match self.rooms.get_mut(room_name).and_then(|room| room.remove(&id)) {
+            Some(member) => member,
+            None => return,
+        };
+        if let Some(info) = self.infos.get_mut(room_name) {
+            info.forget_member(id);
+        }
+
+        let left = ChatEvent::Left {
+            room: room_name.to_owned(),
+            name: member.name,
+        };
+        self.send_chat_message(room_name, left, id);
+        self.collect_room(room_name);
+    }
+
+    /// Tells rooms about members that stopped sending typing notices.
+    fn expire_typing(&mut self) {
+        let now = Instant::now();
+        let mut stopped = Vec::new();
+
+        for (room_name, info) in self.infos.iter_mut() {
+            let expired: Vec<usize> = info.typing.iter()
+                .filter(|(_, typist)| now.duration_since(typist.seen) >= TYPING_TIMEOUT)
+                .map(|(id, _)| *id)
+                .collect();
+
+            for id in expired {
+                info.typing.remove(&id);
+                stopped.push((room_name.clone(), id));
+            }
+        }
+
+        for (room_name, id) in stopped {
+            let name = match self.member_name(&room_name, id) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let msg = ChatEvent::StoppedTyping { room: room_name.clone(), name };
+            self.send_chat_message(&room_name, msg, id);
+        }
+    }
+
+    /// Drops bans and mutes that ran out.
+    fn expire_sanctions(&mut self) {
+        for info in self.infos.values_mut() {
+            info.bans.retain(|_, sanction| sanction.is_active());
+            info.mutes.retain(|_, sanction| sanction.is_active());
+        }
+    }
+
+    /// Empty rooms other than Main are forgotten along with their owner, bans and mutes.
+    /// Their history is kept, to be replayed when the room is joined again.
+    fn collect_room(&mut self, room_name: &str) {
+        if room_name == "Main" {
+            return;
+        }
+
+        if self.rooms.get(room_name).is_some_and(|room| room.is_empty()) {
+            self.rooms.remove(room_name);
+            self.infos.remove(room_name);
+        }
+    }
 }
 
 impl Actor for WsChatServer {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.subscribe_system_async::<LeaveRoom>(ctx);
-        self.subscribe_system_async::<SendMessage>(ctx);
+        self.history = History::from_env();
+        self.last_message_id = self.history.get_last_id();
+
+        ctx.run_interval(TYPING_CHECK_INTERVAL, |act, _ctx| act.expire_typing());
+        ctx.run_interval(SANCTION_CHECK_INTERVAL, |act, _ctx| act.expire_sanctions());
+        ctx.run_interval(FLUSH_INTERVAL, |act, _ctx| act.history.flush());
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.history.flush();
+    }
+}
+
+impl Handler<CreateRoom> for WsChatServer {
+    type Result = MessageResult<CreateRoom>;
+
+    fn handle(&mut self, msg: CreateRoom, _ctx: &mut Self::Context) -> Self::Result {
+        let CreateRoom(room_name, owner, options) = msg;
+
+        if self.infos.contains_key(&room_name) {
+            let message = format!("room {} already exists", room_name);
+            return MessageResult(Err(ProtocolError::new(ErrorCode::RoomExists, &message)));
+        }
+
+        self.rooms.insert(room_name.clone(), HashMap::new());
+        self.infos.insert(room_name, RoomInfo { owner: Some(owner), options, ..RoomInfo::default() });
+
+        MessageResult(Ok(()))
     }
 }
 
@@ -72,17 +495,220 @@ impl Handler<JoinRoom> for WsChatServer {
     type Result = MessageResult<JoinRoom>;
 
     fn handle(&mut self, msg: JoinRoom, _ctx: &mut Self::Context) -> Self::Result {
-        let JoinRoom(room_name, client_name, client) = msg;
+        let JoinRoom(room_name, session, password, client) = msg;
+
+        let name = match self.user_name(session) {
+            Ok(name) => name,
+            Err(e) => return MessageResult(Err(e)),
+        };
+
+        match self.infos.get(&room_name) {
+            Some(info) => {
+                let members = self.rooms.get(&room_name).map_or(0, |room| room.len());
+                let peer = self.user_peer(session);
+
+                if let Err(e) = info.check_join(&room_name, session, &name, peer, password.as_deref(), members) {
+                    return MessageResult(Err(e));
+                }
+            }
+            // The first member of a room other than Main owns it
+            None if room_name != "Main" => {
+                let info = RoomInfo { owner: Some(session), ..RoomInfo::default() };
+                self.infos.insert(room_name.clone(), info);
+            }
+            None => {}
+        }
+
+        let messages = self.history.last(&room_name, self.history.get_replay());
+        if !messages.is_empty() {
+            let replay = ChatEvent::History { room: room_name.clone(), messages };
+            let _ = client.do_send(ChatMessage(replay));
+        }
 
-        let id = self.add_client_to_room(&room_name, None, client);
-        let join_msg = format!(
-            "{} joined {}",
-            client_name.unwrap_or_else(|| "anon".to_string()),
-            room_name
-        );
+        let id = self.add_client_to_room(&room_name, Member { session, name: name.clone(), client });
+        let join_msg = ChatEvent::Joined {
+            room: room_name.clone(),
+            name,
+        };
 
-        self.send_chat_message(&room_name, &join_msg, id);
-        MessageResult(id)
+        self.send_chat_message(&room_name, join_msg, id);
+        MessageResult(Ok(id))
+    }
+}
+
+impl Handler<GetHistory> for WsChatServer {
+    type Result = MessageResult<GetHistory>;
+
+    fn handle(&mut self, msg: GetHistory, _ctx: &mut Self::Context) -> Self::Result {
+        let GetHistory(room_name, id, limit) = msg;
+
+        if !self.rooms.get(&room_name).is_some_and(|room| room.contains_key(&id)) {
+            let message = format!("you are not in room {}", room_name);
+            return MessageResult(Err(ProtocolError::new(ErrorCode::NotFound, &message)));
+        }
+
+        let limit = limit.unwrap_or_else(|| self.history.get_replay());
+        MessageResult(Ok(self.history.last(&room_name, limit)))
+    }
+}
+
+impl Handler<ClaimName> for WsChatServer {
+    type Result = MessageResult<ClaimName>;
+
+    fn handle(&mut self, msg: ClaimName, _ctx: &mut Self::Context) -> Self::Result {
+        let ClaimName(session, requested, peer, client) = msg;
+        let session = session.filter(|session| self.users.contains_key(session));
+
+        let name = match requested {
+            Some(name) => {
+                if let Err(e) = validate_name(&name) {
+                    return MessageResult(Err(e));
+                }
+
+                match self.names.get(&name_key(&name)) {
+                    Some(holder) if Some(*holder) != session => {
+                        let message = format!("name {} is already taken", name);
+                        return MessageResult(Err(ProtocolError::new(ErrorCode::NameTaken, &message)));
+                    }
+                    _ => name,
+                }
+            }
+            None => self.guest_name(),
+        };
+
+        let session = match session {
+            Some(session) => session,
+            None => {
+                self.last_session_id += 1;
+                let session = self.last_session_id;
+
+                self.names.insert(name_key(&name), session);
+                self.users.insert(session, User { name: name.clone(), peer, client, blocked: HashSet::new() });
+                return MessageResult(Ok((session, name)));
+            }
+        };
+
+        let current = match self.users.get_mut(&session) {
+            Some(user) => std::mem::replace(&mut user.name, name.clone()),
+            None => return MessageResult(Err(ProtocolError::new(ErrorCode::NotFound, "claim a name first"))),
+        };
+
+        if current != name {
+            self.names.remove(&name_key(&current));
+            self.names.insert(name_key(&name), session);
+            self.rename_member(session, &current, &name);
+
+            // A rename does not lift bans and mutes
+            for info in self.infos.values_mut() {
+                for sanctions in [&mut info.bans, &mut info.mutes] {
+                    if let Some(sanction) = sanctions.remove(&name_key(&current)) {
+                        sanctions.insert(name_key(&name), sanction);
+                    }
+                }
+            }
+        }
+
+        MessageResult(Ok((session, name)))
+    }
+}
+
+impl Handler<ReleaseName> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReleaseName, _ctx: &mut Self::Context) {
+        let ReleaseName(session) = msg;
+
+        if let Some(user) = self.users.remove(&session) {
+            self.names.remove(&name_key(&user.name));
+        }
+
+        // Session ids are never given out again, whatever was granted to this one ends here.
+        // Bans and mutes are kept by name and address, so they outlast the session.
+        for info in self.infos.values_mut() {
+            info.operators.remove(&session);
+        }
+        for user in self.users.values_mut() {
+            user.blocked.remove(&session);
+        }
+    }
+}
+
+impl Handler<Whisper> for WsChatServer {
+    type Result = MessageResult<Whisper>;
+
+    fn handle(&mut self, msg: Whisper, _ctx: &mut Self::Context) -> Self::Result {
+        let Whisper(session, to, text) = msg;
+
+        let from = match self.user_name(session) {
+            Ok(from) => from,
+            Err(e) => return MessageResult(Err(e)),
+        };
+        let recipient = match self.session_of(&to) {
+            Ok(recipient) => recipient,
+            Err(e) => return MessageResult(Err(e)),
+        };
+
+        if recipient == session {
+            return MessageResult(Err(ProtocolError::new(ErrorCode::BadRequest, "cannot whisper to yourself")));
+        }
+
+        let offline = ProtocolError::new(ErrorCode::Offline, &format!("{} is offline", to));
+        let user = match self.users.get(&recipient) {
+            Some(user) => user,
+            None => return MessageResult(Err(offline)),
+        };
+
+        if user.blocked.contains(&session) {
+            let message = format!("{} does not accept your messages", to);
+            return MessageResult(Err(ProtocolError::new(ErrorCode::Blocked, &message)));
+        }
+
+        // The session may be gone without having released its name yet
+        if user.client.do_send(ChatMessage(ChatEvent::Whisper { from, text })).is_err() {
+            return MessageResult(Err(offline));
+        }
+
+        MessageResult(Ok(()))
+    }
+}
+
+impl Handler<SetBlocked> for WsChatServer {
+    type Result = MessageResult<SetBlocked>;
+
+    fn handle(&mut self, msg: SetBlocked, _ctx: &mut Self::Context) -> Self::Result {
+        let SetBlocked(session, target, blocked) = msg;
+
+        let target_session = match self.session_of(&target) {
+            Ok(target_session) => target_session,
+            Err(e) => return MessageResult(Err(e)),
+        };
+        let user = match self.users.get_mut(&session) {
+            Some(user) => user,
+            None => return MessageResult(Err(ProtocolError::new(ErrorCode::NotFound, "claim a name first"))),
+        };
+
+        let changed = if blocked {
+            user.blocked.insert(target_session)
+        } else {
+            user.blocked.remove(&target_session)
+        };
+
+        if !changed {
+            let message = format!("{} is already {}", target, if blocked { "blocked" } else { "not blocked" });
+            return MessageResult(Err(ProtocolError::new(ErrorCode::BadRequest, &message)));
+        }
+
+        MessageResult(Ok(()))
+    }
+}
+
+impl Handler<Moderate> for WsChatServer {
+    type Result = MessageResult<Moderate>;
+
+    fn handle(&mut self, msg: Moderate, _ctx: &mut Self::Context) -> Self::Result {
+        let Moderate(room_name, actor, command) = msg;
+
+        MessageResult(self.moderate(&room_name, actor, command))
     }
 }
 
@@ -90,28 +716,303 @@ impl Handler<LeaveRoom> for WsChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: LeaveRoom, _ctx: &mut Self::Context) {
-        if let Some(room) = self.rooms.get_mut(&msg.0) {
-            room.remove(&msg.1);
-        }
+        let LeaveRoom(room_name, id) = msg;
+
+        self.remove_client(&room_name, id);
     }
 }
 
 impl Handler<ListRooms> for WsChatServer {
     type Result = MessageResult<ListRooms>;
 
-    fn handle(&mut self, _: ListRooms, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(self.rooms.keys().cloned().collect())
+    fn handle(&mut self, msg: ListRooms, _ctx: &mut Self::Context) -> Self::Result {
+        let ListRooms(session) = msg;
+        let name = self.user_name(session).unwrap_or_default();
+
+        let mut rooms: Vec<String> = self.rooms.iter()
+            .filter(|(room_name, room)| {
+                self.infos.get(*room_name).is_none_or(|info| info.is_visible(session, &name, room))
+            })
+            .map(|(room_name, _)| room_name.clone())
+            .collect();
+        rooms.sort();
+
+        MessageResult(rooms)
     }
 }
 
 impl Handler<SendMessage> for WsChatServer {
+    type Result = MessageResult<SendMessage>;
+
+    fn handle(&mut self, msg: SendMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let SendMessage(room_name, id, text) = msg;
+
+        let (peer, from) = match self.member(&room_name, id) {
+            Ok(member) => (self.user_peer(member.session), member.name.clone()),
+            Err(e) => return MessageResult(Err(e)),
+        };
+
+        let info = self.infos.entry(room_name.clone()).or_default();
+        if is_sanctioned(&info.mutes, &from, peer) {
+            let message = format!("you are muted in room {}", room_name);
+            return MessageResult(Err(ProtocolError::new(ErrorCode::Muted, &message)));
+        }
+
+        // Sending ends typing and implies having read the room so far
+        self.last_message_id += 1;
+        let message_id = self.last_message_id;
+        info.typing.remove(&id);
+        info.receipts.insert(id, message_id);
+
+        self.history.push(HistoryEntry::new(message_id, &room_name, &from, &text));
+
+        let msg = ChatEvent::Message {
+            id: message_id,
+            room: room_name.clone(),
+            from,
+            text,
+        };
+
+        self.send_chat_message(&room_name, msg, id);
+        MessageResult(Ok(message_id))
+    }
+}
+
+impl Handler<Typing> for WsChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: SendMessage, _ctx: &mut Self::Context) {
-        let SendMessage(room_name, id, msg) = msg;
-        self.send_chat_message(&room_name, &msg, id);
+    fn handle(&mut self, msg: Typing, _ctx: &mut Self::Context) {
+        let Typing(room_name, id) = msg;
+
+        let name = match self.member_name(&room_name, id) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+
+        let now = Instant::now();
+        let info = self.infos.entry(room_name.clone()).or_default();
+        let forward = match info.typing.get_mut(&id) {
+            Some(typist) => {
+                typist.seen = now;
+
+                let forward = now.duration_since(typist.sent) >= TYPING_THROTTLE;
+                if forward {
+                    typist.sent = now;
+                }
+                forward
+            }
+            None => {
+                info.typing.insert(id, Typist { sent: now, seen: now });
+                true
+            }
+        };
+
+        if forward {
+            self.send_chat_message(&room_name, ChatEvent::Typing { room: room_name.clone(), name }, id);
+        }
+    }
+}
+
+impl Handler<MarkRead> for WsChatServer {
+    type Result = MessageResult<MarkRead>;
+
+    fn handle(&mut self, msg: MarkRead, _ctx: &mut Self::Context) -> Self::Result {
+        let MarkRead(room_name, id, message) = msg;
+
+        let name = match self.member_name(&room_name, id) {
+            Ok(name) => name,
+            Err(e) => return MessageResult(Err(e)),
+        };
+
+        if message == 0 || message > self.last_message_id {
+            return MessageResult(Err(ProtocolError::new(ErrorCode::NotFound, &format!("no message #{}", message))));
+        }
+
+        let info = self.infos.entry(room_name.clone()).or_default();
+        let read = info.receipts.entry(id).or_insert(0);
+
+        // Receipts only move forward, older ones are already implied
+        if *read < message {
+            *read = message;
+            self.send_chat_message(&room_name, ChatEvent::Read { room: room_name.clone(), name, message }, id);
+        }
+
+        MessageResult(Ok(()))
+    }
+}
+
+impl Handler<GetReceipts> for WsChatServer {
+    type Result = MessageResult<GetReceipts>;
+
+    fn handle(&mut self, msg: GetReceipts, _ctx: &mut Self::Context) -> Self::Result {
+        let GetReceipts(room_name, id, message) = msg;
+
+        if let Err(e) = self.member_name(&room_name, id) {
+            return MessageResult(Err(e));
+        }
+
+        let mut names: Vec<String> = match self.infos.get(&room_name) {
+            Some(info) => info.receipts.iter()
+                .filter(|(_, read)| **read >= message)
+                .filter_map(|(id, _)| self.member_name(&room_name, *id).ok())
+                .collect(),
+            None => Vec::new(),
+        };
+        names.sort();
+
+        MessageResult(Ok(names))
     }
 }
 
 impl SystemService for WsChatServer {}
 impl Supervised for WsChatServer {}
+
+#[cfg(test)]
+mod test {
+    use actix::prelude::*;
+
+    use std::net::IpAddr;
+
+    use crate::message::{
+        ClaimName, Command, CreateRoom, ErrorCode, GetHistory, GetReceipts, JoinRoom, LeaveRoom, MarkRead, Moderate, ReleaseName,
+        RoomOptions, SendMessage, SetBlocked, Target, Whisper,
+    };
+    use crate::outbox::{Client, Flush, Outbox};
+    use crate::server::WsChatServer;
+
+    struct Session;
+
+    impl Actor for Session {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Flush> for Session {
+        type Result = ();
+
+        fn handle(&mut self, _msg: Flush, _ctx: &mut Self::Context) {}
+    }
+
+    fn client() -> Client {
+        Client::new(Outbox::default(), Session.start().recipient())
+    }
+
+    async fn claim(server: &Addr<WsChatServer>, session: Option<usize>, name: &str) -> usize {
+        server.send(ClaimName(session, Some(name.to_owned()), None, client())).await.unwrap().unwrap().0
+    }
+
+    async fn connect(server: &Addr<WsChatServer>, name: &str, peer: Option<IpAddr>) -> usize {
+        server.send(ClaimName(None, Some(name.to_owned()), peer, client())).await.unwrap().unwrap().0
+    }
+
+    async fn join(server: &Addr<WsChatServer>, room: &str, session: usize) -> Result<usize, ErrorCode> {
+        server.send(JoinRoom(room.to_owned(), session, None, client())).await.unwrap().map_err(|e| e.code)
+    }
+
+    #[actix::test]
+    async fn test_ban_follows_rename() {
+        let server = WsChatServer::default().start();
+        let alice = claim(&server, None, "alice").await;
+        let troll = claim(&server, None, "troll").await;
+        let alice_id = join(&server, "rust", alice).await.unwrap();
+        join(&server, "rust", troll).await.unwrap();
+
+        let ban = Command::Ban { target: Target::Name("troll".to_owned()), minutes: None, reason: None };
+        server.send(Moderate("rust".to_owned(), alice_id, ban)).await.unwrap().unwrap();
+
+        assert_eq!(claim(&server, Some(troll), "angel").await, troll);
+        assert_eq!(join(&server, "rust", troll).await, Err(ErrorCode::Banned));
+    }
+
+    #[actix::test]
+    async fn test_ban_outlasts_session() {
+        let server = WsChatServer::default().start();
+        let peer = Some("10.0.0.2".parse().unwrap());
+        let alice = claim(&server, None, "alice").await;
+        let troll = connect(&server, "troll", peer).await;
+        let alice_id = join(&server, "rust", alice).await.unwrap();
+        let troll_id = join(&server, "rust", troll).await.unwrap();
+
+        let ban = Command::Ban { target: Target::Name("troll".to_owned()), minutes: None, reason: None };
+        server.send(Moderate("rust".to_owned(), alice_id, ban)).await.unwrap().unwrap();
+        server.send(ReleaseName(troll)).await.unwrap();
+        server.send(LeaveRoom("rust".to_owned(), troll_id)).await.unwrap();
+
+        let troll = connect(&server, "troll", None).await;
+        assert_eq!(join(&server, "rust", troll).await, Err(ErrorCode::Banned));
+        server.send(ReleaseName(troll)).await.unwrap();
+
+        let angel = connect(&server, "angel", peer).await;
+        assert_eq!(join(&server, "rust", angel).await, Err(ErrorCode::Banned));
+
+        let unban = Command::Unban { name: "troll".to_owned() };
+        server.send(Moderate("rust".to_owned(), alice_id, unban)).await.unwrap().unwrap();
+        assert!(join(&server, "rust", angel).await.is_ok());
+    }
+
+    #[actix::test]
+    async fn test_collected_room_keeps_history() {
+        let server = WsChatServer::default().start();
+        let alice = claim(&server, None, "alice").await;
+        let bob = claim(&server, None, "bob").await;
+
+        let alice_id = join(&server, "rust", alice).await.unwrap();
+        server.send(SendMessage("rust".to_owned(), alice_id, "hi".to_owned())).await.unwrap().unwrap();
+        server.send(LeaveRoom("rust".to_owned(), alice_id)).await.unwrap();
+
+        let bob_id = join(&server, "rust", bob).await.unwrap();
+        let history = server.send(GetHistory("rust".to_owned(), bob_id, None)).await.unwrap().unwrap();
+        assert_eq!(history.iter().map(|entry| entry.text.as_str()).collect::<Vec<_>>(), vec!["hi"]);
+    }
+
+    #[actix::test]
+    async fn test_block_follows_rename() {
+        let server = WsChatServer::default().start();
+        let alice = claim(&server, None, "alice").await;
+        let troll = claim(&server, None, "troll").await;
+
+        server.send(SetBlocked(alice, "troll".to_owned(), true)).await.unwrap().unwrap();
+        claim(&server, Some(troll), "angel").await;
+
+        let whispered = server.send(Whisper(troll, "alice".to_owned(), "hi".to_owned())).await.unwrap();
+        assert_eq!(whispered.unwrap_err().code, ErrorCode::Blocked);
+
+        server.send(ReleaseName(troll)).await.unwrap();
+        let bob = claim(&server, None, "troll").await;
+        server.send(Whisper(bob, "alice".to_owned(), "hi".to_owned())).await.unwrap().unwrap();
+    }
+
+    #[actix::test]
+    async fn test_receipts_follow_rename() {
+        let server = WsChatServer::default().start();
+        let alice = claim(&server, None, "alice").await;
+        let bob = claim(&server, None, "bob").await;
+        let alice_id = join(&server, "rust", alice).await.unwrap();
+        let bob_id = join(&server, "rust", bob).await.unwrap();
+
+        let message = server.send(SendMessage("rust".to_owned(), alice_id, "hi".to_owned())).await.unwrap().unwrap();
+        server.send(MarkRead("rust".to_owned(), bob_id, message)).await.unwrap().unwrap();
+        claim(&server, Some(bob), "robert").await;
+
+        let seen = server.send(GetReceipts("rust".to_owned(), alice_id, message)).await.unwrap().unwrap();
+        assert_eq!(seen, vec!["alice".to_owned(), "robert".to_owned()]);
+    }
+
+    #[actix::test]
+    async fn test_released_name_keeps_no_rights() {
+        let server = WsChatServer::default().start();
+        let alice = claim(&server, None, "alice").await;
+        let bob = claim(&server, None, "bob").await;
+        server.send(CreateRoom("rust".to_owned(), alice, RoomOptions::default())).await.unwrap().unwrap();
+        join(&server, "rust", alice).await.unwrap();
+        join(&server, "rust", bob).await.unwrap();
+
+        server.send(ReleaseName(alice)).await.unwrap();
+        let mallory = claim(&server, None, "alice").await;
+        assert_ne!(mallory, alice);
+
+        let mallory_id = join(&server, "rust", mallory).await.unwrap();
+        let kick = Command::Kick { target: Target::Name("bob".to_owned()), reason: None };
+        let kicked = server.send(Moderate("rust".to_owned(), mallory_id, kick)).await.unwrap();
+        assert_eq!(kicked.unwrap_err().code, ErrorCode::NotOperator);
+    }
+}
@@ -1,44 +1,314 @@
-use log::{debug, info};
+use log::{debug, error, info};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
 
 use actix::fut;
 use actix::prelude::*;
-use actix_broker::BrokerIssue;
 use actix_web_actors::ws;
 
-use crate::message::{ChatMessage, JoinRoom, LeaveRoom, ListRooms, SendMessage};
+use crate::message::{
+    parse_command, parse_request, ChatEvent, ClaimName, Command, CreateRoom, ErrorCode,
+    GetHistory, GetReceipts, JoinRoom, LeaveRoom, ListRooms, MarkRead, Moderate, ProtocolError,
+    ReleaseName, Reply, Response, RoomOptions, SendMessage, SetBlocked, Typing, Whisper,
+    JSON_PROTOCOL, PROTOCOL_VERSION,
+};
+use crate::outbox::{Client, Flush, Outbox};
 use crate::server::WsChatServer;
 
+/// Sessions speak JSON when the client asked for the `JSON_PROTOCOL` subprotocol at the
+/// handshake, and the terminal client's plain text format otherwise.
+///
+/// A session stays in every room it joined until it parts them, `room` is the active one
+/// that messages and moderation commands go to by default. `session` is the id the server
+/// gave out on the first name claim.
 #[derive(Default)]
 pub struct WsChatSession {
-    id: usize,
+    session: Option<usize>,
+    peer: Option<IpAddr>,
+    rooms: HashMap<String, usize>,
     room: String,
     name: Option<String>,
+    json: bool,
+    outbox: Outbox,
 }
 
 impl WsChatSession {
-    pub fn join_room(&mut self, room_name: &str, ctx: &mut ws::WebsocketContext<Self>) {
-        let room_name = room_name.to_owned();
+    /// Session for a handshake from `peer` offering `protocols`, the values of
+    /// `Sec-WebSocket-Protocol`.
+    pub fn new(protocols: &str, peer: Option<IpAddr>) -> Self {
+        WsChatSession {
+            peer,
+            json: protocols.split(',').any(|protocol| protocol.trim() == JSON_PROTOCOL),
+            ..WsChatSession::default()
+        }
+    }
+
+    pub fn get_outbox(&self) -> Outbox {
+        self.outbox.clone()
+    }
+
+    fn client(&self, ctx: &mut ws::WebsocketContext<Self>) -> Client {
+        Client::new(self.outbox.clone(), ctx.address().recipient())
+    }
+
+    fn deliver(&mut self, event: ChatEvent, ctx: &mut ws::WebsocketContext<Self>) {
+        // The server already dropped us from a room we were kicked or banned from
+        if let ChatEvent::Removed { room, .. } = &event {
+            if self.rooms.remove(room).is_some() && self.room == *room {
+                self.room = self.fallback_room();
+            }
+        }
+
+        if self.json {
+            self.send_response(&Response::Event { v: PROTOCOL_VERSION, event }, ctx);
+        } else {
+            self.write(event.to_text(), ctx);
+        }
+    }
+    fn session_id(&self) -> Result<usize, ProtocolError> {
+        self.session.ok_or_else(|| ProtocolError::new(ErrorCode::NotFound, "claim a name first"))
+    }
+
+    /// Member id of this session in `room_name`.
+    fn member_id(&self, room_name: &str) -> Result<usize, ProtocolError> {
+        self.rooms.get(room_name)
+            .copied()
+            .ok_or_else(|| ProtocolError::new(ErrorCode::NotFound, &format!("you are not in room {}", room_name)))
+    }
+
+    /// Active room once the active one was left: Main if still joined, else any other.
+    fn fallback_room(&self) -> String {
+        if self.rooms.contains_key("Main") {
+            return "Main".to_owned();
+        }
+
+        self.rooms.keys().min().cloned().unwrap_or_default()
+    }
+
+    /// Writes a frame, counted until the connection takes it.
+    fn write(&self, text: String, ctx: &mut ws::WebsocketContext<Self>) {
+        self.outbox.wrote(text.len());
+        ctx.text(text);
+    }
+
+    fn send_response(&self, response: &Response, ctx: &mut ws::WebsocketContext<Self>) {
+        match serde_json::to_string(response) {
+            Ok(text) => self.write(text, ctx),
+            Err(e) => error!("Cannot serialize response: {}", e),
+        }
+    }
+
+    fn reply(&self, id: Option<String>, reply: Reply, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.json {
+            self.send_response(&Response::Ok { v: PROTOCOL_VERSION, id, reply }, ctx);
+        } else {
+            for line in reply.to_lines() {
+                self.write(line, ctx);
+            }
+        }
+    }
+
+    fn reply_error(&self, id: Option<String>, error: ProtocolError, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.json {
+            let ProtocolError { code, message } = error;
+            self.send_response(&Response::Error { v: PROTOCOL_VERSION, id, code, message }, ctx);
+        } else {
+            self.write(format!("!!! {}", error.message), ctx);
+        }
+    }
 
-        // First send a leave message for the current room
-        let leave_msg = LeaveRoom(self.room.clone(), self.id);
+    fn execute(&mut self, id: Option<String>, command: Command, ctx: &mut ws::WebsocketContext<Self>) {
+        match command {
+            Command::Create { room, options } => self.create_room(room, options, id, ctx),
+            Command::Join { room, password } => self.join_room(&room, password, id, ctx),
+            Command::Leave => self.part_room(None, id, ctx),
+            Command::Part { room } => self.part_room(room, id, ctx),
+            Command::Switch { room } => match self.member_id(&room) {
+                Ok(_) => {
+                    self.room = room.clone();
+                    self.reply(id, Reply::Switched { room }, ctx);
+                }
+                Err(e) => self.reply_error(id, e, ctx),
+            },
+            Command::List => self.list_rooms(id, ctx),
+            Command::History { room, limit } => self.history(room, limit, id, ctx),
+            Command::Rename { name } => {
+                let name = name.trim();
+                if name.is_empty() {
+                    self.reply_error(id, ProtocolError::new(ErrorCode::MissingArgument, "name is required"), ctx);
+                    return;
+                }
 
-        // issue_sync comes from having the `BrokerIssue` trait in scope.
-        self.issue_system_sync(leave_msg, ctx);
+                self.claim_name(Some(name.to_owned()), id, ctx);
+            }
+            Command::Send { room, text } => self.send_msg(room, text, id, ctx),
+            Command::Typing { room } => {
+                let room = room.unwrap_or_else(|| self.room.clone());
+
+                match self.member_id(&room) {
+                    Ok(member_id) => WsChatServer::from_registry().do_send(Typing(room, member_id)),
+                    Err(e) => self.reply_error(id, e, ctx),
+                }
+            }
+            Command::Read { room, message } => self.mark_read(room, message, id, ctx),
+            Command::Seen { room, message } => self.receipts(room, message, id, ctx),
+            Command::Whisper { to, text } => self.whisper(to, text, id, ctx),
+            Command::Block { name } => self.set_blocked(name, true, id, ctx),
+            Command::Unblock { name } => self.set_blocked(name, false, id, ctx),
+            command => self.moderate(command, id, ctx),
+        }
+    }
+
+    pub fn moderate(&mut self, command: Command, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = self.room.clone();
+        let member_id = match self.member_id(&room_name) {
+            Ok(member_id) => member_id,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
 
-        // Then send a join message for the new room
+        WsChatServer::from_registry()
+            .send(Moderate(room_name.clone(), member_id, command))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.reply(request_id, Reply::Moderated { room: room_name }, ctx),
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Claims `name`, or a guest name for a new session, which then joins Main.
+    pub fn claim_name(&mut self, name: Option<String>, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        WsChatServer::from_registry()
+            .send(ClaimName(self.session, name, self.peer, self.client(ctx)))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok((session, name))) => {
+                        let first = act.session.is_none();
+                        act.session = Some(session);
+                        act.name = Some(name.clone());
+
+                        if first {
+                            act.join_room("Main", None, None, ctx);
+                        } else {
+                            act.reply(request_id, Reply::Renamed { name }, ctx);
+                        }
+                    }
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn create_room(&mut self, room_name: String, options: RoomOptions, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let session = match self.session_id() {
+            Ok(session) => session,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
+        let create_msg = CreateRoom(room_name.clone(), session, options);
+
+        WsChatServer::from_registry()
+            .send(create_msg)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {
+                        act.reply(request_id, Reply::Created { room: room_name.clone() }, ctx);
+                        act.join_room(&room_name, None, None, ctx);
+                    }
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Joins `room_name`, or switches to it when already joined, and makes it active.
+    pub fn join_room(&mut self, room_name: &str, password: Option<String>, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = room_name.to_owned();
+
+        if self.rooms.contains_key(&room_name) {
+            self.room = room_name.clone();
+            self.reply(request_id, Reply::Joined { room: room_name }, ctx);
+            return;
+        }
+
+        let session = match self.session_id() {
+            Ok(session) => session,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
         let join_msg = JoinRoom(
             room_name.to_owned(),
-            self.name.clone(),
-            ctx.address().recipient(),
+            session,
+            password,
+            self.client(ctx),
         );
 
         WsChatServer::from_registry()
             .send(join_msg)
             .into_actor(self)
-            .then(|id, act, _ctx| {
-                if let Ok(id) = id {
-                    act.id = id;
-                    act.room = room_name;
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(id)) => {
+                        act.rooms.insert(room_name.clone(), id);
+                        act.room = room_name.clone();
+                        act.reply(request_id, Reply::Joined { room: room_name }, ctx);
+                    }
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Leaves `room_name`, or the active room, and falls back to another joined room.
+    pub fn part_room(&mut self, room_name: Option<String>, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = room_name.unwrap_or_else(|| self.room.clone());
+
+        match self.rooms.remove(&room_name) {
+            Some(id) => {
+                WsChatServer::from_registry().do_send(LeaveRoom(room_name.clone(), id));
+
+                if self.room == room_name {
+                    self.room = self.fallback_room();
+                }
+                self.reply(request_id, Reply::Left { room: room_name }, ctx);
+            }
+            None => {
+                let message = format!("you are not in room {}", room_name);
+                self.reply_error(request_id, ProtocolError::new(ErrorCode::NotFound, &message), ctx);
+            }
+        }
+    }
+
+    pub fn history(&mut self, room_name: Option<String>, limit: Option<usize>, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = room_name.unwrap_or_else(|| self.room.clone());
+        let member_id = match self.member_id(&room_name) {
+            Ok(member_id) => member_id,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
+
+        WsChatServer::from_registry()
+            .send(GetHistory(room_name.clone(), member_id, limit))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(messages)) => act.reply(request_id, Reply::History { room: room_name, messages }, ctx),
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
                 }
 
                 fut::ready(())
@@ -46,15 +316,18 @@ impl WsChatSession {
             .wait(ctx);
     }
 
-    pub fn list_rooms(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+    pub fn list_rooms(&mut self, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let session = match self.session_id() {
+            Ok(session) => session,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
+
         WsChatServer::from_registry()
-            .send(ListRooms)
+            .send(ListRooms(session))
             .into_actor(self)
-            .then(|res, _, ctx| {
+            .then(|res, act, ctx| {
                 if let Ok(rooms) = res {
-                    for room in rooms {
-                        ctx.text(room);
-                    }
+                    act.reply(request_id, Reply::Rooms { rooms }, ctx);
                 }
 
                 fut::ready(())
@@ -62,17 +335,113 @@ impl WsChatSession {
             .wait(ctx);
     }
 
-    pub fn send_msg(&self, msg: &str) {
-        let content = format!(
-            "{}: {}",
-            self.name.clone().unwrap_or_else(|| "anon".to_string()),
-            msg
-        );
+    pub fn send_msg(&mut self, room_name: Option<String>, msg: String, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = room_name.unwrap_or_else(|| self.room.clone());
+        let member_id = match self.member_id(&room_name) {
+            Ok(member_id) => member_id,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
+
+        WsChatServer::from_registry()
+            .send(SendMessage(room_name.clone(), member_id, msg))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(message)) => act.reply(request_id, Reply::Sent { room: room_name, message }, ctx),
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
+                }
 
-        let msg = SendMessage(self.room.clone(), self.id, content);
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn whisper(&mut self, to: String, text: String, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let session = match self.session_id() {
+            Ok(session) => session,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
+
+        WsChatServer::from_registry()
+            .send(Whisper(session, to.clone(), text))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.reply(request_id, Reply::Whispered { to }, ctx),
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
+                }
 
-        // issue_async comes from having the `BrokerIssue` trait in scope.
-        self.issue_system_async(msg);
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn set_blocked(&mut self, target: String, blocked: bool, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let session = match self.session_id() {
+            Ok(session) => session,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
+
+        WsChatServer::from_registry()
+            .send(SetBlocked(session, target.clone(), blocked))
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) if blocked => act.reply(request_id, Reply::Blocked { name: target }, ctx),
+                    Ok(Ok(())) => act.reply(request_id, Reply::Unblocked { name: target }, ctx),
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn mark_read(&mut self, room_name: Option<String>, message: u64, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = room_name.unwrap_or_else(|| self.room.clone());
+        let member_id = match self.member_id(&room_name) {
+            Ok(member_id) => member_id,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
+
+        WsChatServer::from_registry()
+            .send(MarkRead(room_name.clone(), member_id, message))
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.reply(request_id, Reply::Read { room: room_name, message }, ctx),
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn receipts(&mut self, room_name: Option<String>, message: u64, request_id: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = room_name.unwrap_or_else(|| self.room.clone());
+        let member_id = match self.member_id(&room_name) {
+            Ok(member_id) => member_id,
+            Err(e) => return self.reply_error(request_id, e, ctx),
+        };
+
+        WsChatServer::from_registry()
+            .send(GetReceipts(room_name.clone(), member_id, message))
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(by)) => act.reply(request_id, Reply::Seen { room: room_name, message, by }, ctx),
+                    Ok(Err(e)) => act.reply_error(request_id, e, ctx),
+                    Err(e) => error!("WsChatServer is unavailable: {}", e),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
     }
 }
 
@@ -80,24 +449,44 @@ impl Actor for WsChatSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.join_room("Main", ctx);
+        self.claim_name(None, None, ctx);
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(session) = self.session {
+            WsChatServer::from_registry().do_send(ReleaseName(session));
+        }
+
+        for (room_name, id) in self.rooms.iter() {
+            WsChatServer::from_registry().do_send(LeaveRoom(room_name.clone(), *id));
+        }
+
         info!(
-            "WsChatSession closed for {}({}) in room {}",
+            "WsChatSession closed for {} in rooms {:?}",
             self.name.clone().unwrap_or_else(|| "anon".to_string()),
-            self.id,
-            self.room
+            self.rooms
         );
     }
 }
 
-impl Handler<ChatMessage> for WsChatSession {
+impl Handler<Flush> for WsChatSession {
     type Result = ();
 
-    fn handle(&mut self, msg: ChatMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+    fn handle(&mut self, _msg: Flush, ctx: &mut Self::Context) {
+        if self.outbox.is_closed() {
+            info!("Disconnect {:?}, too slow to keep up", self.name);
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("too slow to keep up".to_owned()),
+            }));
+            ctx.stop();
+            return;
+        }
+
+        // Whatever does not fit the write window waits for the connection to catch up
+        while let Some(event) = self.outbox.next() {
+            self.deliver(event, ctx);
+        }
     }
 }
 
@@ -117,35 +506,18 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             ws::Message::Text(text) => {
                 let msg = text.trim();
 
-                if msg.starts_with('/') {
-                    let mut command = msg.splitn(2, ' ');
-
-                    match command.next() {
-                        Some("/list") => self.list_rooms(ctx),
-
-                        Some("/join") => {
-                            if let Some(room_name) = command.next() {
-                                self.join_room(room_name, ctx);
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-
-                        Some("/name") => {
-                            if let Some(name) = command.next() {
-                                self.name = Some(name.to_owned());
-                                ctx.text(format!("name changed to: {}", name));
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-
-                        _ => ctx.text(format!("!!! unknown command: {:?}", msg)),
+                if self.json {
+                    match parse_request(msg) {
+                        Ok(request) => self.execute(request.id, request.command, ctx),
+                        Err((id, error)) => self.reply_error(id, error, ctx),
                     }
-
                     return;
                 }
-                self.send_msg(msg);
+
+                match parse_command(msg) {
+                    Ok(command) => self.execute(None, command, ctx),
+                    Err(error) => self.reply_error(None, error, ctx),
+                }
             }
 
             ws::Message::Close(_) => {
@@ -0,0 +1,365 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::mpsc::Sender as ThreadSender;
+use std::thread;
+
+use crate::e1::{AdvancedOrder, Balances, KucoinWebsocketMsg};
+use crate::kucoin::client::{Credentials, KucoinClient};
+use crate::kucoin::decimal::Decimal;
+use crate::settings::account::AccountSettings;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Balance {
+    pub currency: String,
+    pub total: Decimal,
+    pub available: Decimal,
+    pub hold: Decimal,
+    pub time: i64,
+}
+
+/// Open lend or borrow order on the margin market.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Loan {
+    pub order_id: String,
+    pub currency: String,
+    pub side: String,
+    pub daily_int_rate: Decimal,
+    pub term: i32,
+    pub size: Decimal,
+    pub lent_size: Decimal,
+    pub ts: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Margin {
+    pub debt_ratio: Option<Decimal>,
+    pub total_debt: Option<Decimal>,
+    pub debts: BTreeMap<String, Decimal>,
+    pub status: Option<String>,
+    pub timestamp: i64,
+}
+
+/// Account state at `version`, the number of changes applied so far.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AccountSnapshot {
+    pub version: u64,
+    pub balances: BTreeMap<String, Balance>,
+    pub stop_orders: BTreeMap<String, AdvancedOrder>,
+    pub loans: BTreeMap<String, Loan>,
+    pub margin: Margin,
+}
+
+#[derive(Debug, Clone)]
+pub enum AccountChange {
+    Balance(Balance),
+    StopOrderPlaced(AdvancedOrder),
+    StopOrderRemoved { order_id: String, reason: String },
+    Margin(Margin),
+    Loan(Loan),
+    LoanDone { order_id: String, reason: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub version: u64,
+    pub change: AccountChange,
+}
+
+/// Account state built from the private balance, stop order and margin streams. Changes
+/// are applied under one lock, so snapshots never mix versions.
+#[derive(Default)]
+pub struct AccountTracker {
+    state: Mutex<AccountSnapshot>,
+    subscribers: Mutex<Vec<ThreadSender<Notification>>>,
+}
+
+impl AccountTracker {
+    pub fn new() -> Self {
+        AccountTracker::default()
+    }
+
+    pub fn snapshot(&self) -> AccountSnapshot {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn get_balance(&self, currency: &str) -> Option<Balance> {
+        self.state.lock().unwrap().balances.get(currency).cloned()
+    }
+
+    /// Every change applied after this call is sent to the returned receiver.
+    pub fn subscribe(&self) -> Receiver<Notification> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Applies an account message. Market data changes nothing.
+    pub fn apply(&self, msg: &KucoinWebsocketMsg) -> Option<Notification> {
+        let mut state = self.state.lock().unwrap();
+
+        let change = match msg {
+            KucoinWebsocketMsg::BalancesMsg(msg) => apply_balance(&mut state.balances, &msg.data)?,
+            KucoinWebsocketMsg::AdvancedOrderMsg(msg) => match msg.data.r#type.as_str() {
+                "open" => {
+                    state.stop_orders.insert(msg.data.order_id.clone(), msg.data.clone());
+                    AccountChange::StopOrderPlaced(msg.data.clone())
+                }
+                r#type => {
+                    state.stop_orders.remove(&msg.data.order_id)?;
+                    AccountChange::StopOrderRemoved { order_id: msg.data.order_id.clone(), reason: r#type.to_string() }
+                }
+            },
+            KucoinWebsocketMsg::DebtRatioMsg(msg) => {
+                state.margin.debt_ratio = Some(msg.data.debt_ratio);
+                state.margin.total_debt = Some(msg.data.total_debt);
+                state.margin.debts = msg.data.debt_list.iter().map(|(k, v)| (k.clone(), *v)).collect();
+                state.margin.timestamp = msg.data.timestamp;
+                AccountChange::Margin(state.margin.clone())
+            }
+            KucoinWebsocketMsg::PositionChangeMsg(msg) => {
+                state.margin.status = Some(msg.data.r#type.clone());
+                state.margin.timestamp = msg.data.timestamp;
+                AccountChange::Margin(state.margin.clone())
+            }
+            KucoinWebsocketMsg::MarginTradeOpenMsg(msg) => {
+                let loan = Loan {
+                    order_id: msg.data.order_id.clone(),
+                    currency: msg.data.currency.clone(),
+                    side: msg.data.side.clone(),
+                    daily_int_rate: msg.data.daily_int_rate,
+                    term: msg.data.term,
+                    size: msg.data.size,
+                    lent_size: Decimal::ZERO,
+                    ts: msg.data.ts,
+                };
+                state.loans.insert(loan.order_id.clone(), loan.clone());
+                AccountChange::Loan(loan)
+            }
+            KucoinWebsocketMsg::MarginTradeUpdateMsg(msg) => {
+                let loan = Loan {
+                    order_id: msg.data.order_id.clone(),
+                    currency: msg.data.currency.clone(),
+                    side: msg.data.side.clone(),
+                    daily_int_rate: msg.data.daily_int_rate,
+                    term: msg.data.term,
+                    size: msg.data.size,
+                    lent_size: msg.data.lent_size,
+                    ts: msg.data.ts,
+                };
+                state.loans.insert(loan.order_id.clone(), loan.clone());
+                AccountChange::Loan(loan)
+            }
+            KucoinWebsocketMsg::MarginTradeDoneMsg(msg) => {
+                state.loans.remove(&msg.data.order_id);
+                AccountChange::LoanDone { order_id: msg.data.order_id.clone(), reason: msg.data.reason.clone() }
+            }
+            _ => return None,
+        };
+
+        state.version += 1;
+        let notification = Notification { version: state.version, change: change };
+
+        self.subscribers.lock().unwrap().retain(|tx| tx.send(notification.clone()).is_ok());
+
+        Some(notification)
+    }
+}
+
+/// Applies the change amounts to a known balance, in any order since changes add up. The first
+/// message for a currency is taken as is; later absolute amounts are only checked for drift.
+fn apply_balance(balances: &mut BTreeMap<String, Balance>, data: &Balances) -> Option<AccountChange> {
+    let time = match data.time.parse::<i64>() {
+        Ok(time) => time,
+        Err(_) => {
+            warn!("{} balance with invalid time {:?}", data.currency, data.time);
+            return None;
+        }
+    };
+
+    let balance = match balances.get(&data.currency) {
+        Some(balance) => {
+            let mut balance = balance.clone();
+            balance.available += data.available_change;
+            balance.hold += data.hold_change;
+            balance.total = balance.available + balance.hold;
+
+            if time >= balance.time && (balance.available, balance.hold) != (data.available, data.hold) {
+                warn!("{} balance {}/{} differs from reported {}/{}", data.currency, balance.available, balance.hold, data.available, data.hold);
+            }
+
+            balance.time = cmp::max(balance.time, time);
+            balance
+        }
+        None => Balance {
+            currency: data.currency.clone(),
+            total: data.total,
+            available: data.available,
+            hold: data.hold,
+            time: time,
+        }
+    };
+
+    balances.insert(data.currency.clone(), balance.clone());
+    Some(AccountChange::Balance(balance))
+}
+
+/// Feeds `tracker` from a private stream until the client stops.
+pub fn track(rx: Receiver<KucoinWebsocketMsg>, tracker: Arc<AccountTracker>) {
+    for msg in rx {
+        tracker.apply(&msg);
+    }
+}
+
+/// Connects to the private account topics and returns the tracker they feed.
+pub fn spawn(settings: &AccountSettings) -> Arc<AccountTracker> {
+    let credentials = Credentials::new(
+        settings.get_api_key().as_str(),
+        settings.get_api_secret().as_str(),
+        settings.get_api_passphrase().as_str(),
+    );
+    let rx = KucoinClient::new(settings.get_rest_url().as_str(), settings.get_topics())
+        .with_credentials(credentials)
+        .spawn();
+
+    let tracker = Arc::new(AccountTracker::new());
+    let tracked = tracker.clone();
+    thread::spawn(move || track(rx, tracked));
+
+    tracker
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::account::{AccountChange, AccountTracker, track};
+    use crate::e1::{KucoinWebsocketMsg, parse_text};
+    use crate::kucoin::client::{Credentials, KucoinClient};
+    use crate::kucoin::decimal::Decimal;
+    use crate::kucoin::mock::MockExchange;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn msg(fixture: &str) -> KucoinWebsocketMsg {
+        parse_text(fixture).unwrap()
+    }
+
+    fn balance(available: &str, available_change: &str, hold: &str, hold_change: &str, time: &str) -> KucoinWebsocketMsg {
+        let total = d(available) + d(hold);
+        msg(json!({
+            "type": "message",
+            "topic": "/account/balance",
+            "subject": "account.balance",
+            "data": {
+                "total": total, "available": available, "availableChange": available_change,
+                "currency": "KCS", "hold": hold, "holdChange": hold_change,
+                "relationEvent": "trade.hold", "relationEventId": "1", "time": time, "accountId": "a",
+            },
+        }).to_string().as_str())
+    }
+
+    #[test]
+    fn test_balances() {
+        let tracker = AccountTracker::new();
+        tracker.apply(&msg(include_str!("../../fixtures/kucoin/balances.json"))).unwrap();
+        tracker.apply(&balance("80", "-8", "8", "8", "1545743136995")).unwrap();
+
+        let kcs = tracker.get_balance("KCS").unwrap();
+        assert_eq!((d("88"), d("80"), d("8")), (kcs.total, kcs.available, kcs.hold));
+
+        tracker.apply(&balance("88", "8", "0", "-8", "1545743136990")).unwrap();
+        let kcs = tracker.get_balance("KCS").unwrap();
+        assert_eq!((d("88"), d("0"), 1545743136995), (kcs.available, kcs.hold, kcs.time));
+
+        tracker.apply(&balance("70", "-1", "8", "0", "1545743136996")).unwrap();
+        let kcs = tracker.get_balance("KCS").unwrap();
+        assert_eq!((d("87"), d("0"), d("87")), (kcs.available, kcs.hold, kcs.total));
+    }
+
+    #[test]
+    fn test_track_private_stream() {
+        let exchange = MockExchange::start(vec![("/account/balance", vec![include_str!("../../fixtures/kucoin/balances.json")])], false);
+        let rx = KucoinClient::new(exchange.rest_url.as_str(), vec!["/account/balance".to_string()])
+            .with_credentials(Credentials::new("key", "secret", "passphrase"))
+            .spawn();
+
+        let tracker = Arc::new(AccountTracker::new());
+        let notifications = tracker.subscribe();
+        let tracked = tracker.clone();
+        thread::spawn(move || track(rx, tracked));
+
+        assert!(notifications.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(tracker.get_balance("KCS").is_some());
+
+        let requests = exchange.requests();
+        assert!(requests[0].starts_with("POST /api/v1/bullet-private"));
+        assert!(requests[0].to_lowercase().contains("kc-api-passphrase:"));
+        assert!(exchange.received().iter().any(|frame| frame.contains("\"privateChannel\":true")));
+    }
+
+    #[test]
+    fn test_track_stop_orders() {
+        let frames = vec![
+            include_str!("../../fixtures/kucoin/advanced_order_open.json"),
+            include_str!("../../fixtures/kucoin/advanced_order_triggered.json"),
+        ];
+        let exchange = MockExchange::start(vec![("/spotMarket/advancedOrders", frames)], false);
+        let rx = KucoinClient::new(exchange.rest_url.as_str(), vec!["/spotMarket/advancedOrders".to_string()])
+            .with_credentials(Credentials::new("key", "secret", "passphrase"))
+            .spawn();
+
+        let tracker = Arc::new(AccountTracker::new());
+        let notifications = tracker.subscribe();
+        let tracked = tracker.clone();
+        thread::spawn(move || track(rx, tracked));
+
+        match notifications.recv_timeout(Duration::from_secs(5)).unwrap().change {
+            AccountChange::StopOrderPlaced(order) => assert_eq!(d("0.00062"), order.stop_price),
+            other => panic!("{:?}", other),
+        }
+        match notifications.recv_timeout(Duration::from_secs(5)).unwrap().change {
+            AccountChange::StopOrderRemoved { order_id, reason } => {
+                assert_eq!("5ec244f6a8a75e0009958237", order_id.as_str());
+                assert_eq!("triggered", reason.as_str());
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(tracker.snapshot().stop_orders.is_empty());
+    }
+
+    #[test]
+    fn test_margin() {
+        let tracker = AccountTracker::new();
+        let rx = tracker.subscribe();
+
+        assert!(tracker.apply(&msg(include_str!("../../fixtures/kucoin/stop_order.json"))).is_none());
+        tracker.apply(&msg(include_str!("../../fixtures/kucoin/debt_ratio.json"))).unwrap();
+        tracker.apply(&msg(include_str!("../../fixtures/kucoin/position_status.json"))).unwrap();
+        tracker.apply(&msg(include_str!("../../fixtures/kucoin/margin_trade_open.json"))).unwrap();
+        tracker.apply(&msg(include_str!("../../fixtures/kucoin/margin_trade_update.json"))).unwrap();
+        assert!(tracker.apply(&msg(include_str!("../../fixtures/kucoin/ticker.json"))).is_none());
+
+        let snapshot = tracker.snapshot();
+        assert_eq!(4, snapshot.version);
+        assert_eq!(Some(d("0.7505")), snapshot.margin.debt_ratio);
+        assert_eq!(Some("FROZEN_FL"), snapshot.margin.status.as_ref().map(|s| s.as_str()));
+        assert_eq!(d("0.5"), snapshot.loans["ac928c66ca53498f9c13a127a60e8"].lent_size);
+
+        tracker.apply(&msg(include_str!("../../fixtures/kucoin/margin_trade_done.json"))).unwrap();
+        assert!(tracker.snapshot().loans.is_empty());
+
+        let notifications: Vec<_> = rx.try_iter().collect();
+        assert_eq!(5, notifications.len());
+        assert_eq!(5, notifications[4].version);
+        match &notifications[4].change {
+            AccountChange::LoanDone { reason, .. } => assert_eq!("filled", reason.as_str()),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use rocket::http::{Cookies, Status};
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use crate::account::{AccountSnapshot, AccountTracker};
+use crate::session::USER_ID_COOKIE;
+use crate::settings::account::AccountSettings;
+
+/// Fails with `Unauthorized` without a login and with `Forbidden` for a user that is not
+/// one of the account's `readers`.
+fn authorize(user_id: Option<&str>, readers: &[String]) -> Result<(), Status> {
+    match user_id {
+        Some(user_id) if readers.iter().any(|reader| reader == user_id) => Ok(()),
+        Some(_) => Err(Status::Forbidden),
+        None => Err(Status::Unauthorized),
+    }
+}
+
+/// Current state of the tracked exchange account, for the account's readers only.
+#[get("/")]
+pub fn snapshot(mut cookies: Cookies, settings: State<AccountSettings>, tracker: State<Arc<AccountTracker>>) -> Result<Json<AccountSnapshot>, Status> {
+    let user_id = cookies.get_private(USER_ID_COOKIE).map(|cookie| cookie.value().to_string());
+    authorize(user_id.as_ref().map(|user_id| user_id.as_str()), &settings.get_readers())?;
+
+    Ok(Json(tracker.snapshot()))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::Status;
+
+    use crate::api_account::authorize;
+
+    #[test]
+    fn test_authorize() {
+        let readers = vec!["5e1f".to_string()];
+
+        assert_eq!(Ok(()), authorize(Some("5e1f"), &readers));
+        assert_eq!(Err(Status::Forbidden), authorize(Some("5e20"), &readers));
+        assert_eq!(Err(Status::Forbidden), authorize(Some("5e1f"), &[]));
+        assert_eq!(Err(Status::Unauthorized), authorize(None, &readers));
+    }
+}
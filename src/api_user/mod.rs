@@ -1,23 +1,189 @@
-use rocket::http::{Cookie, Cookies};
+use std::collections::HashMap;
+
+use chrono::Utc;
+use rocket::http::{Cookie, Cookies, Status};
 use rocket::http::RawStr;
 use rocket::response::{Flash, Redirect};
+use rocket::State;
 use rocket_contrib::json::Json;
+use url::Url;
+
+use crate::session::{CHANNELS_COOKIE, USER_ID_COOKIE};
+use crate::settings::auth::Authorization;
+use crate::settings::ws::{get_connect_string, WsServer};
+use crate::user::store::{StoreError, User, Users};
+use crate::utils::{normalize_group, scope_public_key, scope_user, sign_token};
 
 /// Retrieve the user's ID, if any.
 #[get("/user_id")]
 pub fn user_id(mut cookies: Cookies) -> Option<String> {
     cookies
-        .get_private("user_id")
+        .get_private(USER_ID_COOKIE)
         .map(|cookie| format!("User ID: {}", cookie.value()))
 }
 
-/// Remove the `user_id` cookie.
+/// Remove the `user_id` and `channels` cookies.
 #[post("/logout")]
 pub fn logout(mut cookies: Cookies) -> Flash<Redirect> {
-    cookies.remove_private(Cookie::named("user_id"));
+    cookies.remove_private(Cookie::named(USER_ID_COOKIE));
+    cookies.remove_private(Cookie::named(CHANNELS_COOKIE));
     Flash::success(Redirect::to("/"), "Successfully logged out.")
 }
 
+#[derive(Debug, Deserialize)]
+pub struct CredentialsPayload {
+    name: String,
+    password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    user_id: String,
+    name: String,
+    token: String,
+    nonce: String,
+    channels: String,
+}
+
+/// Log in with a name and password, setting the `user_id` cookie.
+#[post("/login", data = "<payload>")]
+pub fn login(mut cookies: Cookies, users: State<Users>, auth: State<Authorization>, payload: Json<CredentialsPayload>) -> Result<Json<SessionResponse>, Status> {
+    match users.authenticate(payload.name.as_str(), payload.password.as_str()) {
+        Ok(user) => Ok(start_session(&mut cookies, &auth, user)),
+        Err(StoreError::InvalidCredentials) => Err(Status::Unauthorized),
+        Err(e) => {
+            error!("{}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Create a new user and log it in.
+#[post("/register", data = "<payload>")]
+pub fn register(mut cookies: Cookies, users: State<Users>, auth: State<Authorization>, payload: Json<CredentialsPayload>) -> Result<Json<SessionResponse>, Status> {
+    if payload.name.trim().is_empty() || payload.password.is_empty() {
+        return Err(Status::BadRequest);
+    }
+
+    match users.register(payload.name.trim(), payload.password.as_str(), &auth.get_default_channels()) {
+        Ok(user) => Ok(start_session(&mut cookies, &auth, user)),
+        Err(StoreError::AlreadyExists(_)) => Err(Status::Conflict),
+        Err(e) => {
+            error!("{}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Sets the private `user_id` and `channels` cookies and signs a websocket token for clients
+/// that connect without cookies. Both only grant the channels the user may join.
+fn start_session(cookies: &mut Cookies, auth: &Authorization, user: User) -> Json<SessionResponse> {
+    let nonce = format!("{}", Utc::now().timestamp());
+    let channels = user.channels.iter()
+        .map(|channel| normalize_group(channel))
+        .filter(|channel| !channel.is_empty())
+        .collect::<Vec<String>>()
+        .join(",");
+
+    cookies.add_private(Cookie::new(USER_ID_COOKIE, user.id.clone()));
+    cookies.add_private(Cookie::new(CHANNELS_COOKIE, channels.clone()));
+
+    let public_key = scope_user(scope_public_key(nonce.as_str(), channels.as_str()).as_str(), user.id.as_str());
+
+    Json(SessionResponse {
+        token: sign_token(auth.get_private_key().as_str(), public_key.as_str()),
+        nonce: nonce,
+        channels: channels,
+        user_id: user.id,
+        name: user.name,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct WsTokenResponse {
+    user_id: String,
+    token: String,
+    nonce: String,
+    channels: String,
+    expires_at: Option<i64>,
+    urls: HashMap<String, String>,
+}
+
+/// Normalized `requested` channels. Fails with `Forbidden` if one of them is not in
+/// `allowed`, so a token never grants more than the login did.
+fn scope_channels(requested: &str, allowed: &[String]) -> Result<Vec<String>, Status> {
+    let channels: Vec<String> = requested.split(',')
+        .map(normalize_group)
+        .filter(|channel| !channel.is_empty())
+        .collect();
+
+    if channels.is_empty() {
+        return Err(Status::BadRequest);
+    }
+
+    match channels.iter().all(|channel| allowed.contains(channel)) {
+        true => Ok(channels),
+        false => Err(Status::Forbidden),
+    }
+}
+
+/// Issue a websocket token for the logged in user, valid only for the comma separated
+/// `channels`, together with ready to use connect URLs. Only channels of the user's
+/// `channels` cookie can be requested.
+#[get("/ws_token?<channels>")]
+pub fn ws_token(mut cookies: Cookies, auth: State<Authorization>, ws: State<WsServer>, channels: String) -> Result<Json<WsTokenResponse>, Status> {
+    let user_id = match cookies.get_private(USER_ID_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Err(Status::Unauthorized)
+    };
+
+    let allowed: Vec<String> = match cookies.get_private(CHANNELS_COOKIE) {
+        Some(cookie) => cookie.value().split(',').map(normalize_group).filter(|channel| !channel.is_empty()).collect(),
+        None => vec![]
+    };
+
+    let channels = scope_channels(channels.as_str(), &allowed)?.join(",");
+    let now = Utc::now().timestamp();
+    let nonce = format!("{}", now);
+    let public_key = scope_user(scope_public_key(nonce.as_str(), channels.as_str()).as_str(), user_id.as_str());
+    let token = sign_token(auth.get_private_key().as_str(), public_key.as_str());
+
+    let expires_at = match auth.get_keep_alive().unwrap_or(120) {
+        0 => None,
+        keep_alive => Some(now + keep_alive),
+    };
+
+    let scheme = match ws.get_ssl() {
+        Some(_) => "wss",
+        None => "ws",
+    };
+
+    let mut urls = HashMap::new();
+    for channel in channels.split(',') {
+        let mut url = match Url::parse(&format!("{}://{}/{}", scheme, get_connect_string(&ws), channel)) {
+            Ok(url) => url,
+            Err(_) => return Err(Status::BadRequest)
+        };
+
+        url.query_pairs_mut()
+            .append_pair(auth.get_token_name().unwrap_or("token".to_string()).as_str(), token.as_str())
+            .append_pair(auth.get_time_name().unwrap_or("nonce".to_string()).as_str(), nonce.as_str())
+            .append_pair(auth.get_channels_name().unwrap_or("channels".to_string()).as_str(), channels.as_str())
+            .append_pair(auth.get_user_name().unwrap_or("user".to_string()).as_str(), user_id.as_str());
+
+        urls.insert(channel.to_string(), url.to_string());
+    }
+
+    Ok(Json(WsTokenResponse {
+        user_id: user_id,
+        token: token,
+        nonce: nonce,
+        channels: channels,
+        expires_at: expires_at,
+        urls: urls,
+    }))
+}
+
 #[get("/hello/<name>")]
 pub fn hello(name: &RawStr) -> String {
     format!("Hello, {}!", name.as_str())
@@ -44,3 +210,20 @@ pub fn set(payload: Json<UpdateUserPayload>) -> String {
    
     format!("Hello, {}!", payload.x)
 }
+
+#[cfg(test)]
+mod test {
+    use rocket::http::Status;
+
+    use crate::api_user::scope_channels;
+
+    #[test]
+    fn test_scope_channels() {
+        let allowed = vec!["news".to_string(), "hello/world".to_string()];
+
+        assert_eq!(Ok(vec!["news".to_string(), "hello/world".to_string()]), scope_channels("/News,hello/world/", &allowed));
+        assert_eq!(Err(Status::Forbidden), scope_channels("news,admin", &allowed));
+        assert_eq!(Err(Status::Forbidden), scope_channels("news", &[]));
+        assert_eq!(Err(Status::BadRequest), scope_channels(",", &allowed));
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+
+use ws::Response;
+
+/// Reasons a websocket handshake is refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    MissingToken,
+    BadNonce(String),
+    Expired(i64),
+    BadSignature,
+    ForbiddenChannel(String),
+    OriginDenied(String),
+    TooManyConnections(String),
+}
+
+impl AuthError {
+    /// Stable identifier sent to clients in the `error` field.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken => "missing_token",
+            AuthError::BadNonce(_) => "bad_nonce",
+            AuthError::Expired(_) => "expired",
+            AuthError::BadSignature => "bad_signature",
+            AuthError::ForbiddenChannel(_) => "forbidden_channel",
+            AuthError::OriginDenied(_) => "origin_denied",
+            AuthError::TooManyConnections(_) => "too_many_connections",
+        }
+    }
+
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            AuthError::MissingToken => (401, "Unauthorized"),
+            AuthError::BadNonce(_) => (400, "Bad Request"),
+            AuthError::Expired(_) => (401, "Unauthorized"),
+            AuthError::BadSignature => (401, "Unauthorized"),
+            AuthError::ForbiddenChannel(_) => (403, "Forbidden"),
+            AuthError::OriginDenied(_) => (403, "Forbidden"),
+            AuthError::TooManyConnections(_) => (429, "Too Many Requests"),
+        }
+    }
+
+    /// Builds the HTTP response returned from `on_request` instead of the upgrade.
+    pub fn to_response(&self) -> Response {
+        let (status, reason) = self.status();
+        let body = json!({
+            "error": self.code(),
+            "message": format!("{}", self),
+        });
+
+        let mut response = Response::new(status, reason, body.to_string().into_bytes());
+        response.headers_mut().push(("Content-Type".to_string(), b"application/json".to_vec()));
+        response
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "Token or nonce is missing"),
+            AuthError::BadNonce(nonce) => write!(f, "Nonce is not integer: {:?}", nonce),
+            AuthError::Expired(nonce) => write!(f, "Token expired, nonce {}", nonce),
+            AuthError::BadSignature => write!(f, "Token signature is not valid"),
+            AuthError::ForbiddenChannel(channel) => write!(f, "Token does not allow channel [{}]", channel),
+            AuthError::OriginDenied(origin) => write!(f, "Origin [{}] is not allowed", origin),
+            AuthError::TooManyConnections(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+
+    use crate::auth::AuthError;
+
+    #[test]
+    fn test_to_response() {
+        let response = AuthError::Expired(1504970846).to_response();
+        assert_eq!(401, response.status());
+
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!("expired", body["error"]);
+
+        let response = AuthError::ForbiddenChannel("news".to_string()).to_response();
+        assert_eq!(403, response.status());
+    }
+}
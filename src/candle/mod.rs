@@ -0,0 +1,424 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::mpsc::Sender as ThreadSender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use crate::event::{Event, RelayMessage};
+use crate::exchange::{ExchangeAdapter, MarketEvent, MarketUpdate, Trade};
+use crate::exchange::kucoin::KucoinAdapter;
+use crate::kucoin::decimal::Decimal;
+use crate::settings::candle::CandleSettings;
+
+const UNITS: [(&str, i64); 4] = [("d", 86_400_000), ("h", 3_600_000), ("m", 60_000), ("s", 1000)];
+
+/// Bars are finished by the clock at least this often, even while trades keep arriving.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bar length, written as `1s`, `1m`, `5m`, `1h` or `1d` in config and channel names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Interval {
+    millis: i64,
+}
+
+impl Interval {
+    pub fn get_millis(&self) -> i64 {
+        self.millis
+    }
+
+    pub fn start_of(&self, time: i64) -> i64 {
+        time - time.rem_euclid(self.millis)
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.char_indices().last().map(|(i, _)| i).unwrap_or(0);
+        let (count, unit) = s.split_at(split);
+
+        let unit = UNITS.iter().find(|(name, _)| *name == unit).map(|(_, millis)| *millis);
+        match (count.parse::<i64>(), unit) {
+            (Ok(count), Some(unit)) if count > 0 => Ok(Interval { millis: count * unit }),
+            _ => Err(format!("Invalid candle interval {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match UNITS.iter().find(|(_, millis)| self.millis % millis == 0) {
+            Some((name, millis)) => write!(f, "{}{}", self.millis / millis, name),
+            None => write!(f, "{}ms", self.millis),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Candle {
+    pub symbol: String,
+    pub interval: String,
+    pub start: i64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+    pub trades: u64,
+    #[serde(skip)]
+    first_time: i64,
+    #[serde(skip)]
+    last_time: i64,
+}
+
+impl Candle {
+    fn new(trade: &Trade, interval: Interval) -> Self {
+        Candle {
+            symbol: trade.symbol.clone(),
+            interval: interval.to_string(),
+            start: interval.start_of(trade.time),
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: Decimal::ZERO,
+            trades: 0,
+            first_time: trade.time,
+            last_time: trade.time,
+        }
+    }
+
+    /// Open and close follow trade time, so out-of-order trades land where they belong.
+    fn add(&mut self, trade: &Trade) {
+        if trade.time < self.first_time {
+            self.open = trade.price;
+            self.first_time = trade.time;
+        }
+        if trade.time >= self.last_time {
+            self.close = trade.price;
+            self.last_time = trade.time;
+        }
+
+        self.high = cmp::max(self.high, trade.price);
+        self.low = cmp::min(self.low, trade.price);
+        self.volume += trade.size;
+        self.trades += 1;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CandleUpdate {
+    pub finished: bool,
+    pub candle: Candle,
+    /// No newer bar of the same symbol and interval exists, so this one is the channel snapshot.
+    #[serde(skip)]
+    pub latest: bool,
+}
+
+impl CandleUpdate {
+    pub fn get_channel(&self) -> String {
+        candle_channel(self.candle.symbol.as_str(), self.candle.interval.as_str())
+    }
+}
+
+fn candle_channel(symbol: &str, interval: &str) -> String {
+    format!("candles/{}/{}", symbol.to_lowercase(), interval)
+}
+
+/// Builds bars per symbol and interval. A bar is finished once the newest trade of its
+/// symbol, or the clock passed to `flush`, is `grace` past its end; later trades for it
+/// are dropped.
+pub struct Aggregator {
+    intervals: Vec<Interval>,
+    grace: i64,
+    open: BTreeMap<(String, Interval, i64), Candle>,
+    finished: HashMap<(String, Interval), i64>,
+    dropped: u64,
+}
+
+impl Aggregator {
+    pub fn new(intervals: Vec<Interval>, grace: i64) -> Self {
+        Aggregator {
+            intervals: intervals,
+            grace: grace,
+            open: BTreeMap::new(),
+            finished: HashMap::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Trades that arrived after one of their bars was finished.
+    pub fn get_dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn has_newer(&self, symbol: &str, interval: Interval, start: i64) -> bool {
+        self.open.keys().any(|(s, i, st)| s == symbol && *i == interval && *st > start)
+    }
+
+    /// Adds a trade and returns the bars it changed followed by the bars it finished.
+    pub fn add(&mut self, trade: &Trade) -> Vec<CandleUpdate> {
+        let mut updates = Vec::new();
+        let mut dropped = false;
+
+        for interval in self.intervals.clone() {
+            let start = interval.start_of(trade.time);
+            let finished = self.finished.get(&(trade.symbol.clone(), interval)).cloned().unwrap_or(i64::min_value());
+
+            if start < finished {
+                dropped = true;
+                continue;
+            }
+
+            let candle = self.open.entry((trade.symbol.clone(), interval, start)).or_insert_with(|| Candle::new(trade, interval));
+            candle.add(trade);
+            let candle = candle.clone();
+
+            updates.push(CandleUpdate {
+                finished: false,
+                latest: !self.has_newer(trade.symbol.as_str(), interval, start),
+                candle: candle,
+            });
+        }
+
+        if dropped {
+            self.dropped += 1;
+        }
+
+        let symbol = trade.symbol.clone();
+        updates.extend(self.finish(|s| s == symbol.as_str(), trade.time));
+        updates
+    }
+
+    /// Finishes bars of every symbol whose grace window ended before `now`.
+    pub fn flush(&mut self, now: i64) -> Vec<CandleUpdate> {
+        self.finish(|_| true, now)
+    }
+
+    fn finish<F>(&mut self, matches: F, now: i64) -> Vec<CandleUpdate> where F: Fn(&str) -> bool {
+        let grace = self.grace;
+        let done: Vec<(String, Interval, i64)> = self.open.keys()
+            .filter(|(symbol, interval, start)| matches(symbol.as_str()) && start + interval.get_millis() + grace <= now)
+            .cloned()
+            .collect();
+
+        let mut updates = Vec::new();
+        for key in done {
+            if let Some(candle) = self.open.remove(&key) {
+                let (symbol, interval, start) = key;
+                let latest = !self.has_newer(symbol.as_str(), interval, start);
+                let end = self.finished.entry((symbol, interval)).or_insert(i64::min_value());
+                *end = cmp::max(*end, start + interval.get_millis());
+
+                updates.push(CandleUpdate { finished: true, candle: candle, latest: latest });
+            }
+        }
+
+        updates
+    }
+}
+
+/// Aggregates the match stream and publishes every bar change into its candle channel.
+/// The newest bar of a channel, finished or not, is kept as snapshot for joining clients.
+pub fn publish(rx: Receiver<MarketUpdate>, tx: ThreadSender<Event>, mut aggregator: Aggregator) {
+    let mut flushed = Instant::now();
+
+    loop {
+        let mut updates = match rx.recv_timeout(FLUSH_INTERVAL) {
+            Ok(MarketUpdate { event: MarketEvent::Trade(trade), .. }) => aggregator.add(&trade),
+            Ok(_) => vec![],
+            Err(RecvTimeoutError::Timeout) => vec![],
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        if flushed.elapsed() >= FLUSH_INTERVAL {
+            updates.extend(aggregator.flush(Utc::now().timestamp_millis()));
+            flushed = Instant::now();
+        }
+
+        for update in updates {
+            let message = match serde_json::to_string(&update) {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
+                }
+            };
+
+            let relay = RelayMessage {
+                channel: update.get_channel(),
+                message: message,
+                snapshot: update.latest,
+            };
+
+            if tx.send(Event::Relay(relay)).is_err() {
+                error!("Multicast is gone, stop candles");
+                return;
+            }
+        }
+    }
+}
+
+/// Starts the aggregator and returns the candle channels it owns.
+pub fn spawn(settings: &CandleSettings, tx: ThreadSender<Event>) -> Vec<String> {
+    let intervals: Vec<Interval> = settings.get_intervals().iter()
+        .filter_map(|interval| match interval.parse() {
+            Ok(interval) => Some(interval),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        })
+        .collect();
+
+    let adapter = KucoinAdapter::new(settings.get_rest_url().as_str());
+    let topics = settings.get_symbols().iter().map(|symbol| adapter.trade_topic(symbol)).collect();
+    let rx = adapter.connect(topics);
+    let channels = settings.get_symbols().iter()
+        .flat_map(|symbol| intervals.iter().map(move |interval| candle_channel(symbol, interval.to_string().as_str())))
+        .collect();
+    let aggregator = Aggregator::new(intervals, settings.get_grace_ms());
+
+    thread::spawn(move || publish(rx, tx, aggregator));
+    channels
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::candle::{Aggregator, Interval, publish};
+    use crate::event::Event;
+    use crate::exchange::{MarketEvent, MarketUpdate, Side, Trade};
+    use crate::kucoin::decimal::Decimal;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn tick(price: &str, size: &str, time: i64) -> Trade {
+        Trade {
+            symbol: "BTC-USDT".to_string(),
+            trade_id: format!("{}", time),
+            side: Side::Buy,
+            price: d(price),
+            size: d(size),
+            time: time,
+        }
+    }
+
+    #[test]
+    fn test_interval() {
+        let intervals: Vec<Interval> = vec!["1s", "1m", "5m", "1h"].into_iter().map(|i| i.parse().unwrap()).collect();
+
+        assert_eq!(vec![1000, 60_000, 300_000, 3_600_000], intervals.iter().map(|i| i.get_millis()).collect::<Vec<i64>>());
+        assert_eq!("5m", intervals[2].to_string());
+        assert_eq!(120_000, intervals[1].start_of(179_999));
+        assert!("0m".parse::<Interval>().is_err());
+        assert!("1w".parse::<Interval>().is_err());
+        assert!("".parse::<Interval>().is_err());
+    }
+
+    #[test]
+    fn test_out_of_order_within_grace() {
+        let mut aggregator = Aggregator::new(vec!["1s".parse().unwrap()], 500);
+
+        aggregator.add(&tick("10", "1", 1100));
+        aggregator.add(&tick("12", "1", 1900));
+        assert!(aggregator.add(&tick("11", "2", 2200)).iter().all(|u| !u.finished));
+
+        let updates = aggregator.add(&tick("9", "1", 1050));
+        assert_eq!(1, updates.len());
+        let candle = &updates[0].candle;
+        assert_eq!((d("9"), d("12"), d("9"), d("12")), (candle.open, candle.high, candle.low, candle.close));
+        assert_eq!((d("3"), 3), (candle.volume, candle.trades));
+
+        let updates = aggregator.add(&tick("13", "1", 2600));
+        let finished: Vec<_> = updates.iter().filter(|u| u.finished).collect();
+        assert_eq!(1, finished.len());
+        assert_eq!((1000, d("9"), d("12")), (finished[0].candle.start, finished[0].candle.open, finished[0].candle.close));
+        assert_eq!("candles/btc-usdt/1s", finished[0].get_channel());
+
+        assert!(aggregator.add(&tick("1", "1", 1999)).is_empty());
+        assert_eq!(1, aggregator.get_dropped());
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut aggregator = Aggregator::new(vec!["1s".parse().unwrap(), "1m".parse().unwrap()], 500);
+        aggregator.add(&tick("10", "1", 1100));
+
+        assert!(aggregator.flush(2400).is_empty());
+
+        let finished = aggregator.flush(2500);
+        assert_eq!(1, finished.len());
+        assert_eq!("1s", finished[0].candle.interval.as_str());
+
+        assert_eq!(1, aggregator.flush(60_500).len());
+    }
+
+    #[test]
+    fn test_dropped_per_trade() {
+        let mut aggregator = Aggregator::new(vec!["1s".parse().unwrap(), "1m".parse().unwrap()], 500);
+        aggregator.add(&tick("10", "1", 1100));
+        aggregator.add(&tick("11", "1", 2600));
+
+        let updates = aggregator.add(&tick("9", "1", 1999));
+        assert_eq!(1, updates.len());
+        assert_eq!("1m", updates[0].candle.interval.as_str());
+        assert_eq!(1, aggregator.get_dropped());
+    }
+
+    #[test]
+    fn test_latest() {
+        let mut aggregator = Aggregator::new(vec!["1s".parse().unwrap()], 500);
+        aggregator.add(&tick("10", "1", 1100));
+        assert!(aggregator.add(&tick("11", "1", 2200)).iter().all(|u| u.latest));
+
+        let updates = aggregator.add(&tick("9", "1", 1050));
+        assert_eq!((1000, false), (updates[0].candle.start, updates[0].latest));
+
+        let finished: Vec<_> = aggregator.add(&tick("12", "1", 2600)).into_iter().filter(|u| u.finished).collect();
+        assert_eq!((1000, false), (finished[0].candle.start, finished[0].latest));
+
+        let finished = aggregator.flush(3500);
+        assert_eq!((2000, true), (finished[0].candle.start, finished[0].latest));
+    }
+
+    #[test]
+    fn test_publish_flushes_while_busy() {
+        let (trades, rx) = channel();
+        let (tx, events) = channel();
+        thread::spawn(move || publish(rx, tx, Aggregator::new(vec!["1s".parse().unwrap()], 0)));
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let mut finished = None;
+
+        while finished.is_none() && Instant::now() < deadline {
+            trades.send(MarketUpdate {
+                exchange: "kucoin".to_string(),
+                topic: "/market/match:BTC-USDT".to_string(),
+                event: MarketEvent::Trade(tick("10", "1", 1100)),
+                raw: String::new(),
+            }).unwrap();
+            thread::sleep(Duration::from_millis(100));
+
+            finished = events.try_iter().find_map(|event| match event {
+                Event::Relay(message) if message.message.contains("\"finished\":true") => Some(message),
+                _ => None,
+            });
+        }
+
+        let finished = finished.expect("bar was not finished while trades kept arriving");
+        assert_eq!("candles/btc-usdt/1s", finished.channel.as_str());
+        assert!(finished.snapshot);
+    }
+}
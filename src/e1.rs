@@ -1,67 +1,117 @@
-fn parse_message(msg: Message) -> Result<KucoinWebsocketMsg, APIError> {
-    match msg {
-        Message::Text(msg) => {
-            if msg.contains("\"type\":\"welcome\"") || msg.contains("\"type\":\"ack\"") {
-                Ok(KucoinWebsocketMsg::WelcomeMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"type\":\"ping\"") {
-                Ok(KucoinWebsocketMsg::PingMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"type\":\"pong\"") {
-                Ok(KucoinWebsocketMsg::PongMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"subject\":\"trade.ticker\"") {
-                Ok(KucoinWebsocketMsg::TickerMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"topic\":\"/market/ticker:all\"") {
-                Ok(KucoinWebsocketMsg::AllTickerMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"subject\":\"trade.snapshot\"") {
-                Ok(KucoinWebsocketMsg::SnapshotMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"subject\":\"trade.l2update\"") {
-                Ok(KucoinWebsocketMsg::OrderBookMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("/market/match:") {
-                Ok(KucoinWebsocketMsg::MatchMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"subject\":\"trade.l3received\"") {
-                Ok(KucoinWebsocketMsg::Level3ReceivedMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"subject\":\"trade.l3open\"") {
-                Ok(KucoinWebsocketMsg::Level3OpenMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"subject\":\"trade.l3done\"") {
-                Ok(KucoinWebsocketMsg::Level3DoneMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"subject\":\"trade.l3match\"") {
-                Ok(KucoinWebsocketMsg::Level3MatchMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"subject\":\"trade.l3change\"") {
-                Ok(KucoinWebsocketMsg::Level3ChangeMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("/indicator/index:") {
-                Ok(KucoinWebsocketMsg::IndexPriceMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("/indicator/markPrice:") {
-                Ok(KucoinWebsocketMsg::MarketPriceMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("/margin/fundingBook:") {
-                Ok(KucoinWebsocketMsg::OrderBookChangeMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("\"type\":\"stop\"") || msg.contains("\"type\":\"activate\"") {
-                Ok(KucoinWebsocketMsg::StopOrderMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("/account/balance") {
-                Ok(KucoinWebsocketMsg::BalancesMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("debt.ratio")  {
-                Ok(KucoinWebsocketMsg::DebtRatioMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("position.status") {
-                Ok(KucoinWebsocketMsg::PositionChangeMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("order.open") {
-                Ok(KucoinWebsocketMsg::MarginTradeOpenMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("order.update") {
-                Ok(KucoinWebsocketMsg::MarginTradeUpdateMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("order.done") {
-                Ok(KucoinWebsocketMsg::MarginTradeDoneMsg(serde_json::from_str(&msg)?))
-            } else if msg.contains("error") {
-                Ok(KucoinWebsocketMsg::Error(msg))
-            } else {
-                serde::export::Err(APIError::Other("No KucoinWebSocketMsg type to parse".to_string()))
-            }
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use ws::Message;
+
+use crate::kucoin::decimal::Decimal;
+
+#[derive(Debug)]
+pub enum APIError {
+    Serde(serde_json::Error),
+    MissingField(&'static str),
+    UnknownType(String),
+    UnknownTopic { topic: String, subject: String },
+    InvalidPayload { kind: &'static str, error: serde_json::Error },
+    Http(String),
+    Websocket(ws::Error),
+    Other(String),
+}
+
+impl From<serde_json::Error> for APIError {
+    fn from(e: serde_json::Error) -> Self {
+        APIError::Serde(e)
+    }
+}
+
+impl From<ws::Error> for APIError {
+    fn from(e: ws::Error) -> Self {
+        APIError::Websocket(e)
+    }
+}
+
+impl fmt::Display for APIError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            APIError::Serde(e) => write!(f, "Cannot parse message: {}", e),
+            APIError::MissingField(field) => write!(f, "Message has no string field [{}]", field),
+            APIError::UnknownType(r#type) => write!(f, "Unknown message type [{}]", r#type),
+            APIError::UnknownTopic { topic, subject } => write!(f, "Unknown topic [{}] with subject [{}]", topic, subject),
+            APIError::InvalidPayload { kind, error } => write!(f, "Cannot parse {} message: {}", kind, error),
+            APIError::Http(e) => write!(f, "HTTP request failed: {}", e),
+            APIError::Websocket(e) => write!(f, "Websocket failed: {}", e),
+            APIError::Other(e) => write!(f, "{}", e),
         }
+    }
+}
+
+pub fn parse_message(msg: Message) -> Result<KucoinWebsocketMsg, APIError> {
+    match msg {
+        Message::Text(msg) => parse_text(msg.as_str()),
         Message::Binary(b) => Ok(KucoinWebsocketMsg::Binary(b)),
-        Message::Pong(..) => Ok(KucoinWebsocketMsg::Pong),
-        Message::Ping(..) => Ok(KucoinWebsocketMsg::Ping),
-        Message::Close(..) => {
-            serde::export::Err(APIError::Other("Socket closed error".to_string()))
-        }
     }
 }
 
+/// Parses a text frame once and dispatches on its `type`, then on the `topic` prefix
+/// (the part before `:`) and `subject` of data messages.
+pub fn parse_text(msg: &str) -> Result<KucoinWebsocketMsg, APIError> {
+    let value: Value = serde_json::from_str(msg)?;
+
+    match get_str(&value, "type")? {
+        "welcome" | "ack" => Ok(KucoinWebsocketMsg::WelcomeMsg(decode("welcome", value)?)),
+        "ping" => Ok(KucoinWebsocketMsg::PingMsg(decode("ping", value)?)),
+        "pong" => Ok(KucoinWebsocketMsg::PongMsg(decode("pong", value)?)),
+        "subscribe" | "unsubscribe" => Ok(KucoinWebsocketMsg::SubscribeMsg(decode("subscribe", value)?)),
+        "error" => Ok(KucoinWebsocketMsg::Error(msg.to_string())),
+        "message" => parse_data(value),
+        r#type => Err(APIError::UnknownType(r#type.to_string())),
+    }
+}
+
+fn parse_data(value: Value) -> Result<KucoinWebsocketMsg, APIError> {
+    let topic = get_str(&value, "topic")?.to_string();
+    let subject = get_str(&value, "subject")?.to_string();
+    let mut parts = topic.splitn(2, ':');
+    let prefix = parts.next().unwrap_or("");
+    let target = parts.next();
+
+    match (prefix, subject.as_str()) {
+        ("/market/ticker", _) if target == Some("all") => Ok(KucoinWebsocketMsg::AllTickerMsg(decode("all ticker", value)?)),
+        ("/market/ticker", "trade.ticker") => Ok(KucoinWebsocketMsg::TickerMsg(decode("ticker", value)?)),
+        ("/market/snapshot", "trade.snapshot") => Ok(KucoinWebsocketMsg::SnapshotMsg(decode("snapshot", value)?)),
+        ("/market/level2", "trade.l2update") => Ok(KucoinWebsocketMsg::OrderBookMsg(decode("level2", value)?)),
+        ("/market/match", _) => Ok(KucoinWebsocketMsg::MatchMsg(decode("match", value)?)),
+        ("/market/level3", "trade.l3received") => Ok(KucoinWebsocketMsg::Level3ReceivedMsg(decode("level3 received", value)?)),
+        ("/market/level3", "trade.l3open") => Ok(KucoinWebsocketMsg::Level3OpenMsg(decode("level3 open", value)?)),
+        ("/market/level3", "trade.l3match") => Ok(KucoinWebsocketMsg::Level3MatchMsg(decode("level3 match", value)?)),
+        ("/market/level3", "trade.l3done") => Ok(KucoinWebsocketMsg::Level3DoneMsg(decode("level3 done", value)?)),
+        ("/market/level3", "trade.l3change") => Ok(KucoinWebsocketMsg::Level3ChangeMsg(decode("level3 change", value)?)),
+        ("/market/level3", "stop") | ("/market/level3", "activate") => Ok(KucoinWebsocketMsg::StopOrderMsg(decode("stop order", value)?)),
+        ("/indicator/index", _) => Ok(KucoinWebsocketMsg::IndexPriceMsg(decode("index price", value)?)),
+        ("/indicator/markPrice", _) => Ok(KucoinWebsocketMsg::MarketPriceMsg(decode("mark price", value)?)),
+        ("/margin/fundingBook", _) => Ok(KucoinWebsocketMsg::OrderBookChangeMsg(decode("funding book", value)?)),
+        ("/spotMarket/advancedOrders", "stopOrder") => Ok(KucoinWebsocketMsg::AdvancedOrderMsg(decode("advanced order", value)?)),
+        ("/account/balance", _) => Ok(KucoinWebsocketMsg::BalancesMsg(decode("balance", value)?)),
+        ("/margin/position", "debt.ratio") => Ok(KucoinWebsocketMsg::DebtRatioMsg(decode("debt ratio", value)?)),
+        ("/margin/position", "position.status") => Ok(KucoinWebsocketMsg::PositionChangeMsg(decode("position status", value)?)),
+        ("/margin/loan", "order.open") => Ok(KucoinWebsocketMsg::MarginTradeOpenMsg(decode("margin trade open", value)?)),
+        ("/margin/loan", "order.update") => Ok(KucoinWebsocketMsg::MarginTradeUpdateMsg(decode("margin trade update", value)?)),
+        ("/margin/loan", "order.done") => Ok(KucoinWebsocketMsg::MarginTradeDoneMsg(decode("margin trade done", value)?)),
+        _ => Err(APIError::UnknownTopic { topic: topic.clone(), subject: subject.clone() }),
+    }
+}
+
+fn get_str<'a>(value: &'a Value, field: &'static str) -> Result<&'a str, APIError> {
+    value.get(field)
+        .and_then(|v| v.as_str())
+        .ok_or(APIError::MissingField(field))
+}
+
+fn decode<T: DeserializeOwned>(kind: &'static str, value: Value) -> Result<T, APIError> {
+    serde_json::from_value(value).map_err(|error| APIError::InvalidPayload { kind: kind, error: error })
+}
+
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(clippy::large_enum_variant)]
@@ -87,6 +137,7 @@ pub enum KucoinWebsocketMsg {
     MarketPriceMsg(WSResp<MarketPrice>),
     OrderBookChangeMsg(WSResp<BookChange>),
     StopOrderMsg(WSResp<StopOrder>),
+    AdvancedOrderMsg(WSResp<AdvancedOrder>),
     BalancesMsg(WSResp<Balances>),
     DebtRatioMsg(WSResp<DebtRatio>),
     PositionChangeMsg(WSResp<PositionChange>),
@@ -130,12 +181,12 @@ pub struct Subscribe {
 #[serde(rename_all = "camelCase")]
 pub struct SymbolTicker {
     pub sequence: String,
-    pub best_ask: String,
-    pub size: String,
-    pub best_bid_size: String,
-    pub price: String,
-    pub best_ask_size: String,
-    pub best_bid: String,
+    pub best_ask: Decimal,
+    pub size: Decimal,
+    pub best_bid_size: Decimal,
+    pub price: Decimal,
+    pub best_ask_size: Decimal,
+    pub best_bid: Decimal,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -150,21 +201,21 @@ pub struct Snapshot {
 pub struct SnapshotData {
     pub trading: bool,
     pub symbol: String,
-    pub buy: f32,
-    pub sell: f32,
+    pub buy: Decimal,
+    pub sell: Decimal,
     pub sort: i32,
-    pub vol_value: f32,
+    pub vol_value: Decimal,
     pub base_currency: String,
     pub market: String,
     pub quote_currency: String,
     pub symbol_code: String,
     pub datetime: i64,
-    pub high: Option<f32>,
-    pub vol: f32,
-    pub low: Option<f32>,
-    pub change_price: Option<f32>,
-    pub change_rate: f32,
-    pub last_traded_price: f32,
+    pub high: Option<Decimal>,
+    pub vol: Decimal,
+    pub low: Option<Decimal>,
+    pub change_price: Option<Decimal>,
+    pub change_rate: Decimal,
+    pub last_traded_price: Decimal,
     pub board: i32,
     pub mark: i32,
 }
@@ -181,18 +232,22 @@ pub struct Level2 {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Level2Changes {
-    pub asks: Vec<Vec<String>>,
-    pub bids: Vec<Vec<String>>,
+    pub asks: Vec<Level2Change>,
+    pub bids: Vec<Level2Change>,
 }
 
+/// One `[price, size, sequence]` entry of a level 2 update, a zero size removes the level.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Level2Change(pub Decimal, pub Decimal, pub String);
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Match {
     pub sequence: String,
     pub symbol: String,
     pub side: String,
-    pub size: String,
-    pub price: String,
+    pub size: Decimal,
+    pub price: Decimal,
     pub taker_order_id: String,
     pub time: String,
     pub r#type: String,
@@ -207,7 +262,7 @@ pub struct Level3Received {
     pub symbol: String,
     pub side: String,
     pub order_id: String,
-    pub price: Option<String>,
+    pub price: Option<Decimal>,
     pub time: String,
     pub client_oid: Option<String>,
     pub r#type: String,
@@ -220,9 +275,9 @@ pub struct Level3Open {
     pub sequence: String,
     pub symbol: String,
     pub side: String,
-    pub size: String,
+    pub size: Decimal,
     pub order_id: String,
-    pub price: String,
+    pub price: Decimal,
     pub time: String,
     pub r#type: String,
 }
@@ -237,7 +292,7 @@ pub struct Level3Done {
     pub order_id: String,
     pub time: String,
     pub r#type: String,
-    pub size: Option<String>,
+    pub size: Option<Decimal>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -246,8 +301,8 @@ pub struct Level3Match {
     pub sequence: String,
     pub symbol: String,
     pub side: String,
-    pub size: String,
-    pub price: String,
+    pub size: Decimal,
+    pub price: Decimal,
     pub taker_order_id: String,
     pub time: String,
     pub r#type: String,
@@ -262,11 +317,11 @@ pub struct Level3Change {
     pub symbol: String,
     pub side: String,
     pub order_id: String,
-    pub price: String,
-    pub new_size: String,
+    pub price: Decimal,
+    pub new_size: Decimal,
     pub time: String,
     pub r#type: String,
-    pub old_size: String,
+    pub old_size: Decimal,
 }
 
 
@@ -276,7 +331,7 @@ pub struct IndexPrice {
     pub symbol: String,
     pub granularity: i32,
     pub timestamp: i64,
-    pub value: f32,
+    pub value: Decimal,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -285,7 +340,7 @@ pub struct MarketPrice {
     pub symbol: String,
     pub granularity: i32,
     pub timestamp: i64,
-    pub value: f32,
+    pub value: Decimal,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -293,10 +348,10 @@ pub struct MarketPrice {
 pub struct BookChange {
     pub sequence: i32,
     pub currency: String,
-    pub daily_int_rate: f32,
-    pub annual_int_rate: f32,
+    pub daily_int_rate: Decimal,
+    pub annual_int_rate: Decimal,
     pub term: i32,
-    pub size: f32,
+    pub size: Decimal,
     pub side: String,
     pub ts: i64,
 }
@@ -308,22 +363,41 @@ pub struct StopOrder {
     pub symbol: String,
     pub side: String,
     pub order_id: String,
-    pub stop_entry: String,
-    pub funds: String,
+    pub stop_entry: Decimal,
+    pub funds: Decimal,
     pub time: String,
     pub r#type: String,
     pub reason: Option<String>
 }
 
+/// Own stop order on the private `/spotMarket/advancedOrders` topic.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdvancedOrder {
+    pub created_at: i64,
+    pub order_id: String,
+    pub order_price: Decimal,
+    pub order_type: String,
+    pub side: String,
+    pub size: Decimal,
+    pub stop: String,
+    pub stop_price: Decimal,
+    pub symbol: String,
+    pub trade_type: String,
+    pub trigger_success: Option<bool>,
+    pub ts: i64,
+    pub r#type: String,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Balances {
-    pub total: String,
-    pub available: String,
-    pub available_change: String,
+    pub total: Decimal,
+    pub available: Decimal,
+    pub available_change: Decimal,
     pub currency: String,
-    pub hold: String,
-    pub hold_change: String,
+    pub hold: Decimal,
+    pub hold_change: Decimal,
     pub relation_event: String,
     pub relation_event_id: String,
     pub time: String,
@@ -333,9 +407,9 @@ pub struct Balances {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DebtRatio {
-    pub debt_ratio: f32,
-    pub total_debt: String,
-    pub debt_list: HashMap<String, String>,
+    pub debt_ratio: Decimal,
+    pub total_debt: Decimal,
+    pub debt_list: HashMap<String, Decimal>,
     pub timestamp: i64,
 }
 
@@ -351,9 +425,9 @@ pub struct PositionChange {
 pub struct MarginTradeOpen {
     pub currency: String,
     pub order_id: String,
-    pub daily_int_rate: f32,
+    pub daily_int_rate: Decimal,
     pub term: i32,
-    pub size: i32,
+    pub size: Decimal,
     pub side: String,
     pub ts: i64,
 }
@@ -363,10 +437,10 @@ pub struct MarginTradeOpen {
 pub struct MarginTradeUpdate {
     pub currency: String,
     pub order_id: String,
-    pub daily_int_rate: f32,
+    pub daily_int_rate: Decimal,
     pub term: i32,
-    pub size: i32,
-    pub lent_size: f32,
+    pub size: Decimal,
+    pub lent_size: Decimal,
     pub side: String,
     pub ts: i64,
 }
@@ -379,4 +453,141 @@ pub struct MarginTradeDone {
     pub reason: String,
     pub side: String,
     pub ts: i64,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use ws::Message;
+
+    use crate::e1::{APIError, KucoinWebsocketMsg, parse_message, parse_text};
+
+    fn variant(msg: &KucoinWebsocketMsg) -> &'static str {
+        match msg {
+            KucoinWebsocketMsg::WelcomeMsg(_) => "WelcomeMsg",
+            KucoinWebsocketMsg::SubscribeMsg(_) => "SubscribeMsg",
+            KucoinWebsocketMsg::PingMsg(_) => "PingMsg",
+            KucoinWebsocketMsg::PongMsg(_) => "PongMsg",
+            KucoinWebsocketMsg::Ping => "Ping",
+            KucoinWebsocketMsg::Pong => "Pong",
+            KucoinWebsocketMsg::Binary(_) => "Binary",
+            KucoinWebsocketMsg::TickerMsg(_) => "TickerMsg",
+            KucoinWebsocketMsg::AllTickerMsg(_) => "AllTickerMsg",
+            KucoinWebsocketMsg::SnapshotMsg(_) => "SnapshotMsg",
+            KucoinWebsocketMsg::OrderBookMsg(_) => "OrderBookMsg",
+            KucoinWebsocketMsg::MatchMsg(_) => "MatchMsg",
+            KucoinWebsocketMsg::Level3ReceivedMsg(_) => "Level3ReceivedMsg",
+            KucoinWebsocketMsg::Level3OpenMsg(_) => "Level3OpenMsg",
+            KucoinWebsocketMsg::Level3MatchMsg(_) => "Level3MatchMsg",
+            KucoinWebsocketMsg::Level3DoneMsg(_) => "Level3DoneMsg",
+            KucoinWebsocketMsg::Level3ChangeMsg(_) => "Level3ChangeMsg",
+            KucoinWebsocketMsg::IndexPriceMsg(_) => "IndexPriceMsg",
+            KucoinWebsocketMsg::MarketPriceMsg(_) => "MarketPriceMsg",
+            KucoinWebsocketMsg::OrderBookChangeMsg(_) => "OrderBookChangeMsg",
+            KucoinWebsocketMsg::StopOrderMsg(_) => "StopOrderMsg",
+            KucoinWebsocketMsg::AdvancedOrderMsg(_) => "AdvancedOrderMsg",
+            KucoinWebsocketMsg::BalancesMsg(_) => "BalancesMsg",
+            KucoinWebsocketMsg::DebtRatioMsg(_) => "DebtRatioMsg",
+            KucoinWebsocketMsg::PositionChangeMsg(_) => "PositionChangeMsg",
+            KucoinWebsocketMsg::MarginTradeOpenMsg(_) => "MarginTradeOpenMsg",
+            KucoinWebsocketMsg::MarginTradeUpdateMsg(_) => "MarginTradeUpdateMsg",
+            KucoinWebsocketMsg::MarginTradeDoneMsg(_) => "MarginTradeDoneMsg",
+            KucoinWebsocketMsg::Error(_) => "Error",
+        }
+    }
+
+    #[test]
+    fn test_parse_fixtures() {
+        let fixtures = vec![
+            (include_str!("../fixtures/kucoin/welcome.json"), "WelcomeMsg"),
+            (include_str!("../fixtures/kucoin/ack.json"), "WelcomeMsg"),
+            (include_str!("../fixtures/kucoin/ping.json"), "PingMsg"),
+            (include_str!("../fixtures/kucoin/pong.json"), "PongMsg"),
+            (include_str!("../fixtures/kucoin/subscribe.json"), "SubscribeMsg"),
+            (include_str!("../fixtures/kucoin/error.json"), "Error"),
+            (include_str!("../fixtures/kucoin/ticker.json"), "TickerMsg"),
+            (include_str!("../fixtures/kucoin/all_ticker.json"), "AllTickerMsg"),
+            (include_str!("../fixtures/kucoin/snapshot.json"), "SnapshotMsg"),
+            (include_str!("../fixtures/kucoin/level2.json"), "OrderBookMsg"),
+            (include_str!("../fixtures/kucoin/match.json"), "MatchMsg"),
+            (include_str!("../fixtures/kucoin/l3received.json"), "Level3ReceivedMsg"),
+            (include_str!("../fixtures/kucoin/l3open.json"), "Level3OpenMsg"),
+            (include_str!("../fixtures/kucoin/l3match.json"), "Level3MatchMsg"),
+            (include_str!("../fixtures/kucoin/l3done.json"), "Level3DoneMsg"),
+            (include_str!("../fixtures/kucoin/l3change.json"), "Level3ChangeMsg"),
+            (include_str!("../fixtures/kucoin/index_price.json"), "IndexPriceMsg"),
+            (include_str!("../fixtures/kucoin/mark_price.json"), "MarketPriceMsg"),
+            (include_str!("../fixtures/kucoin/funding_book.json"), "OrderBookChangeMsg"),
+            (include_str!("../fixtures/kucoin/stop_order.json"), "StopOrderMsg"),
+            (include_str!("../fixtures/kucoin/stop_order_activate.json"), "StopOrderMsg"),
+            (include_str!("../fixtures/kucoin/advanced_order_open.json"), "AdvancedOrderMsg"),
+            (include_str!("../fixtures/kucoin/advanced_order_triggered.json"), "AdvancedOrderMsg"),
+            (include_str!("../fixtures/kucoin/balances.json"), "BalancesMsg"),
+            (include_str!("../fixtures/kucoin/debt_ratio.json"), "DebtRatioMsg"),
+            (include_str!("../fixtures/kucoin/position_status.json"), "PositionChangeMsg"),
+            (include_str!("../fixtures/kucoin/margin_trade_open.json"), "MarginTradeOpenMsg"),
+            (include_str!("../fixtures/kucoin/margin_trade_update.json"), "MarginTradeUpdateMsg"),
+            (include_str!("../fixtures/kucoin/margin_trade_done.json"), "MarginTradeDoneMsg"),
+        ];
+
+        for (fixture, expected) in fixtures {
+            match parse_text(fixture) {
+                Ok(msg) => assert_eq!(expected, variant(&msg), "{}", fixture),
+                Err(e) => panic!("{}: {}", e, fixture),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_decimals() {
+        match parse_text(include_str!("../fixtures/kucoin/index_price.json")) {
+            Ok(KucoinWebsocketMsg::IndexPriceMsg(msg)) => assert_eq!("0.0001092", msg.data.value.to_string()),
+            other => panic!("{:?}", other),
+        }
+
+        match parse_text(include_str!("../fixtures/kucoin/ticker.json")) {
+            Ok(KucoinWebsocketMsg::TickerMsg(msg)) => assert_eq!(msg.data.best_ask, msg.data.price),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_binary() {
+        let msg = parse_message(Message::Binary(vec![1, 2, 3])).unwrap();
+        assert_eq!("Binary", variant(&msg));
+    }
+
+    #[test]
+    fn test_parse_ignores_words_in_data() {
+        let fixture = include_str!("../fixtures/kucoin/ticker.json")
+            .replace("1545896668986", "error order.done position.status /account/balance");
+
+        assert_eq!("TickerMsg", variant(&parse_text(fixture.as_str()).unwrap()));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        match parse_text("{\"type\":") {
+            Err(APIError::Serde(_)) => {}
+            other => panic!("{:?}", other),
+        }
+
+        match parse_text("{\"id\":\"1\"}") {
+            Err(APIError::MissingField("type")) => {}
+            other => panic!("{:?}", other),
+        }
+
+        match parse_text("{\"id\":\"1\",\"type\":\"notice\"}") {
+            Err(APIError::UnknownType(ref r#type)) if r#type == "notice" => {}
+            other => panic!("{:?}", other),
+        }
+
+        match parse_text("{\"type\":\"message\",\"topic\":\"/market/candles:BTC-USDT_1min\",\"subject\":\"trade.candles.update\",\"data\":{}}") {
+            Err(APIError::UnknownTopic { ref topic, .. }) if topic == "/market/candles:BTC-USDT_1min" => {}
+            other => panic!("{:?}", other),
+        }
+
+        match parse_text("{\"type\":\"message\",\"topic\":\"/market/ticker:BTC-USDT\",\"subject\":\"trade.ticker\",\"data\":{}}") {
+            Err(APIError::InvalidPayload { kind: "ticker", .. }) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -35,9 +35,18 @@ impl MultiCastMessage {
     }
 }
 
+/// Upstream market data republished into `channel`. Snapshots replace the channel's last
+/// message, which is sent to every connection joining it afterwards.
+pub struct RelayMessage {
+    pub channel: String,
+    pub message: String,
+    pub snapshot: bool,
+}
+
 pub enum Event {
     Subscribe((String, Sender, String)),
     UnSubscribe((String, String)),
     Multicast(MultiCastMessage),
+    Relay(RelayMessage),
     Logging(EventMessage),
 }
@@ -0,0 +1,240 @@
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use crate::e1::KucoinWebsocketMsg;
+use crate::exchange::{BookDelta, ExchangeAdapter, MarketEvent, MarketUpdate, Side, Ticker, Trade};
+use crate::kucoin::client::KucoinClient;
+use crate::kucoin::decimal::Decimal;
+
+pub const NAME: &str = "kucoin";
+
+pub struct KucoinAdapter {
+    rest_url: String,
+    backoff: Option<(Duration, Duration)>,
+}
+
+impl KucoinAdapter {
+    pub fn new(rest_url: &str) -> Self {
+        KucoinAdapter {
+            rest_url: rest_url.to_string(),
+            backoff: None,
+        }
+    }
+
+    pub fn with_backoff(mut self, min_backoff: Duration, max_backoff: Duration) -> Self {
+        self.backoff = Some((min_backoff, max_backoff));
+        self
+    }
+}
+
+impl ExchangeAdapter for KucoinAdapter {
+    fn get_name(&self) -> &str {
+        NAME
+    }
+
+    fn ticker_topic(&self, symbol: &str) -> String {
+        format!("/market/ticker:{}", symbol)
+    }
+
+    fn trade_topic(&self, symbol: &str) -> String {
+        format!("/market/match:{}", symbol)
+    }
+
+    fn book_topic(&self, symbol: &str) -> String {
+        format!("/market/level2:{}", symbol)
+    }
+
+    fn normalizes(&self, topic: &str) -> bool {
+        match topic.splitn(2, ':').next() {
+            Some("/market/ticker") | Some("/market/match") | Some("/market/level2") => true,
+            _ => false,
+        }
+    }
+
+    fn connect(&self, topics: Vec<String>) -> Receiver<MarketUpdate> {
+        let mut client = KucoinClient::new(self.rest_url.as_str(), topics);
+        if let Some((min_backoff, max_backoff)) = self.backoff {
+            client = client.with_backoff(min_backoff, max_backoff);
+        }
+
+        let frames = client.spawn_frames();
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            for (text, msg) in frames {
+                if let Some(update) = normalize(text.as_str(), &msg) {
+                    if tx.send(update).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        rx
+    }
+}
+
+/// Normalized form of a Kucoin market data message received as `text`, `None` for control
+/// frames and account data. Trades with an unknown side or time are kept as raw messages.
+pub fn normalize(text: &str, msg: &KucoinWebsocketMsg) -> Option<MarketUpdate> {
+    let raw = MarketEvent::Raw { snapshot: false };
+    let (topic, event) = match msg {
+        KucoinWebsocketMsg::TickerMsg(msg) | KucoinWebsocketMsg::AllTickerMsg(msg) => {
+            let symbol = match msg.topic.splitn(2, ':').nth(1) {
+                Some("all") => msg.subject.clone(),
+                Some(symbol) => symbol.to_string(),
+                None => return None,
+            };
+
+            (&msg.topic, MarketEvent::Ticker(Ticker {
+                symbol: symbol,
+                price: msg.data.price,
+                size: msg.data.size,
+                best_bid: msg.data.best_bid,
+                best_bid_size: msg.data.best_bid_size,
+                best_ask: msg.data.best_ask,
+                best_ask_size: msg.data.best_ask_size,
+            }))
+        }
+        KucoinWebsocketMsg::MatchMsg(msg) => (&msg.topic, trade(&msg.data.symbol, &msg.data.trade_id, &msg.data.side, msg.data.price, msg.data.size, &msg.data.time).unwrap_or(raw)),
+        KucoinWebsocketMsg::Level3MatchMsg(msg) => (&msg.topic, trade(&msg.data.symbol, &msg.data.trade_id, &msg.data.side, msg.data.price, msg.data.size, &msg.data.time).unwrap_or(raw)),
+        KucoinWebsocketMsg::OrderBookMsg(msg) => (&msg.topic, MarketEvent::BookDelta(BookDelta {
+            symbol: msg.data.symbol.clone(),
+            sequence_start: msg.data.sequence_start,
+            sequence_end: msg.data.sequence_end,
+            bids: msg.data.changes.bids.iter().map(|change| (change.0, change.1)).collect(),
+            asks: msg.data.changes.asks.iter().map(|change| (change.0, change.1)).collect(),
+        })),
+        KucoinWebsocketMsg::SnapshotMsg(msg) => (&msg.topic, MarketEvent::Raw { snapshot: true }),
+        KucoinWebsocketMsg::Level3ReceivedMsg(msg) => (&msg.topic, raw),
+        KucoinWebsocketMsg::Level3OpenMsg(msg) => (&msg.topic, raw),
+        KucoinWebsocketMsg::Level3DoneMsg(msg) => (&msg.topic, raw),
+        KucoinWebsocketMsg::Level3ChangeMsg(msg) => (&msg.topic, raw),
+        KucoinWebsocketMsg::IndexPriceMsg(msg) => (&msg.topic, raw),
+        KucoinWebsocketMsg::MarketPriceMsg(msg) => (&msg.topic, raw),
+        KucoinWebsocketMsg::OrderBookChangeMsg(msg) => (&msg.topic, raw),
+        KucoinWebsocketMsg::StopOrderMsg(msg) => (&msg.topic, raw),
+        _ => return None,
+    };
+
+    Some(MarketUpdate {
+        exchange: NAME.to_string(),
+        topic: topic.clone(),
+        event: event,
+        raw: text.to_string(),
+    })
+}
+
+/// Kucoin match times are nanoseconds.
+fn trade(symbol: &str, trade_id: &str, side: &str, price: Decimal, size: Decimal, time: &str) -> Option<MarketEvent> {
+    let side = match Side::parse(side) {
+        Some(side) => side,
+        None => {
+            warn!("{} trade {} with unknown side {:?}", symbol, trade_id, side);
+            return None;
+        }
+    };
+
+    let time = match time.parse::<i64>() {
+        Ok(time) => time / 1_000_000,
+        Err(_) => {
+            warn!("{} trade {} with invalid time {:?}", symbol, trade_id, time);
+            return None;
+        }
+    };
+
+    Some(MarketEvent::Trade(Trade {
+        symbol: symbol.to_string(),
+        trade_id: trade_id.to_string(),
+        side: side,
+        price: price,
+        size: size,
+        time: time,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::e1::parse_text;
+    use crate::exchange::{ExchangeAdapter, MarketEvent, MarketUpdate, Side};
+    use crate::exchange::kucoin::{KucoinAdapter, normalize};
+    use crate::kucoin::decimal::Decimal;
+    use crate::kucoin::mock::MockExchange;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn update(fixture: &str) -> Option<MarketUpdate> {
+        normalize(fixture, &parse_text(fixture).unwrap())
+    }
+
+    #[test]
+    fn test_normalize() {
+        let ticker = update(include_str!("../../fixtures/kucoin/all_ticker.json")).unwrap();
+        match ticker.event {
+            MarketEvent::Ticker(ticker) => assert_eq!(("BTC-USDT", d("0.049")), (ticker.symbol.as_str(), ticker.best_bid)),
+            other => panic!("{:?}", other),
+        }
+
+        let trade = update(include_str!("../../fixtures/kucoin/match.json")).unwrap();
+        assert_eq!("/market/match:BTC-USDT", trade.topic.as_str());
+        match trade.event {
+            MarketEvent::Trade(trade) => assert_eq!((Side::Buy, d("0.082"), 1545913818099), (trade.side, trade.price, trade.time)),
+            other => panic!("{:?}", other),
+        }
+
+        let delta = update(include_str!("../../fixtures/kucoin/level2.json")).unwrap();
+        match delta.event {
+            MarketEvent::BookDelta(delta) => {
+                assert_eq!(vec![(d("4"), d("1"))], delta.bids);
+                assert_eq!(vec![(d("6"), d("1"))], delta.asks);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let index = update(include_str!("../../fixtures/kucoin/index_price.json")).unwrap();
+        assert_eq!(MarketEvent::Raw { snapshot: false }, index.event);
+        assert_eq!(include_str!("../../fixtures/kucoin/index_price.json"), index.raw.as_str());
+
+        let snapshot = update(include_str!("../../fixtures/kucoin/snapshot.json")).unwrap();
+        assert_eq!(MarketEvent::Raw { snapshot: true }, snapshot.event);
+        assert_eq!(include_str!("../../fixtures/kucoin/snapshot.json"), snapshot.raw.as_str());
+
+        assert!(update(include_str!("../../fixtures/kucoin/balances.json")).is_none());
+    }
+
+    #[test]
+    fn test_normalize_unknown_trade() {
+        let fixture = include_str!("../../fixtures/kucoin/match.json").replace("\"side\":\"buy\"", "\"side\":\"short\"");
+        let trade = update(fixture.as_str()).unwrap();
+
+        assert_eq!(MarketEvent::Raw { snapshot: false }, trade.event);
+        assert_eq!(fixture, trade.raw);
+    }
+
+    #[test]
+    fn test_normalizes() {
+        let adapter = KucoinAdapter::new("http://localhost");
+
+        assert!(adapter.normalizes(adapter.ticker_topic("BTC-USDT").as_str()));
+        assert!(adapter.normalizes(adapter.book_topic("BTC-USDT").as_str()));
+        assert!(!adapter.normalizes("/indicator/index:USDT-BTC"));
+        assert!(!adapter.normalizes("/market/level3:BTC-USDT"));
+    }
+
+    #[test]
+    fn test_connect() {
+        let exchange = MockExchange::start(vec![("/market/match:BTC-USDT", vec![include_str!("../../fixtures/kucoin/match.json")])], false);
+        let adapter = KucoinAdapter::new(exchange.rest_url.as_str());
+        let rx = adapter.connect(vec![adapter.trade_topic("BTC-USDT")]);
+
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(update) => assert_eq!(("kucoin", "/market/match:BTC-USDT"), (update.exchange.as_str(), update.topic.as_str())),
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::mpsc::Receiver;
+
+use crate::kucoin::decimal::Decimal;
+
+pub mod kucoin;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn parse(side: &str) -> Option<Side> {
+        match side {
+            "buy" => Some(Side::Buy),
+            "sell" => Some(Side::Sell),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Ticker {
+    pub symbol: String,
+    pub price: Decimal,
+    pub size: Decimal,
+    pub best_bid: Decimal,
+    pub best_bid_size: Decimal,
+    pub best_ask: Decimal,
+    pub best_ask_size: Decimal,
+}
+
+/// Executed trade, `side` is the taker side and `time` is in milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Trade {
+    pub symbol: String,
+    pub trade_id: String,
+    pub side: Side,
+    pub price: Decimal,
+    pub size: Decimal,
+    pub time: i64,
+}
+
+/// Level 2 changes between two sequence numbers, a zero size removes the level.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BookDelta {
+    pub symbol: String,
+    pub sequence_start: i64,
+    pub sequence_end: i64,
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MarketEvent {
+    Ticker(Ticker),
+    Trade(Trade),
+    BookDelta(BookDelta),
+    /// Market data without a normalized form, only available as `MarketUpdate::raw`.
+    /// `snapshot` marks messages carrying the full market state.
+    Raw { snapshot: bool },
+}
+
+/// Normalized event with the exchange topic it was received on.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MarketUpdate {
+    pub exchange: String,
+    pub topic: String,
+    pub event: MarketEvent,
+    /// The message in the exchange's own format.
+    #[serde(skip)]
+    pub raw: String,
+}
+
+/// Market data source. Topics use the exchange's own names, the `*_topic` helpers build
+/// them from a symbol.
+pub trait ExchangeAdapter: Send {
+    fn get_name(&self) -> &str;
+
+    fn ticker_topic(&self, symbol: &str) -> String;
+
+    fn trade_topic(&self, symbol: &str) -> String;
+
+    fn book_topic(&self, symbol: &str) -> String;
+
+    /// Whether `topic` has normalized events, other topics only stream `MarketEvent::Raw`.
+    fn normalizes(&self, topic: &str) -> bool;
+
+    /// Subscribes `topics` and streams their market data, reconnecting as needed.
+    /// The connection is closed once the receiver is dropped.
+    fn connect(&self, topics: Vec<String>) -> Receiver<MarketUpdate>;
+}
@@ -0,0 +1,439 @@
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::mpsc::Sender as ThreadSender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use ws::{CloseCode, Handler, Handshake, Message, Result, Sender};
+use ws::util::Token;
+
+use crate::e1::{APIError, KucoinWebsocketMsg, parse_message, parse_text};
+use crate::kucoin::command::{Command, PendingAcks};
+
+const PING: Token = Token(1);
+const PONG_CHECK: Token = Token(2);
+const ACK_CHECK: Token = Token(3);
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Ids only have to be unique per connection, the timestamp keeps them unique across reconnects.
+pub fn next_id() -> String {
+    format!("{}{}", Utc::now().timestamp_millis(), NEXT_ID.fetch_add(1, Ordering::SeqCst))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceServer {
+    pub endpoint: String,
+    pub encrypt: bool,
+    pub protocol: String,
+    pub ping_interval: u64,
+    pub ping_timeout: u64,
+}
+
+/// Connection token returned by the `bullet-public` and `bullet-private` REST endpoints.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bullet {
+    pub token: String,
+    pub instance_servers: Vec<InstanceServer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulletResp {
+    code: String,
+    data: Option<Bullet>,
+    msg: Option<String>,
+}
+
+/// API key used to sign private REST requests.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub key: String,
+    pub secret: String,
+    pub passphrase: String,
+}
+
+impl Credentials {
+    pub fn new(key: &str, secret: &str, passphrase: &str) -> Self {
+        Credentials {
+            key: key.to_string(),
+            secret: secret.to_string(),
+            passphrase: passphrase.to_string(),
+        }
+    }
+
+    /// Base64 HMAC-SHA256 of `payload` with the API secret.
+    fn sign(&self, payload: &str) -> String {
+        let mut mac = Hmac::new(Sha256::new(), self.secret.as_bytes());
+        mac.input(payload.as_bytes());
+        base64::encode(mac.result().code())
+    }
+}
+
+const BULLET_PUBLIC: &str = "/api/v1/bullet-public";
+const BULLET_PRIVATE: &str = "/api/v1/bullet-private";
+
+pub fn fetch_bullet(rest_url: &str) -> std::result::Result<Bullet, APIError> {
+    let url = format!("{}{}", rest_url.trim_end_matches('/'), BULLET_PUBLIC);
+
+    request_bullet(url.as_str(), reqwest::blocking::Client::new().post(url.as_str()))
+}
+
+/// Token for private topics, signed with the v2 API key scheme.
+pub fn fetch_private_bullet(rest_url: &str, credentials: &Credentials) -> std::result::Result<Bullet, APIError> {
+    let url = format!("{}{}", rest_url.trim_end_matches('/'), BULLET_PRIVATE);
+    let timestamp = format!("{}", Utc::now().timestamp_millis());
+
+    let request = reqwest::blocking::Client::new()
+        .post(url.as_str())
+        .header("KC-API-KEY", credentials.key.as_str())
+        .header("KC-API-SIGN", credentials.sign(format!("{}POST{}", timestamp, BULLET_PRIVATE).as_str()))
+        .header("KC-API-TIMESTAMP", timestamp.as_str())
+        .header("KC-API-PASSPHRASE", credentials.sign(credentials.passphrase.as_str()))
+        .header("KC-API-KEY-VERSION", "2");
+
+    request_bullet(url.as_str(), request)
+}
+
+fn request_bullet(url: &str, request: reqwest::blocking::RequestBuilder) -> std::result::Result<Bullet, APIError> {
+    let resp: BulletResp = request
+        .send()
+        .and_then(|resp| resp.json())
+        .map_err(|e| APIError::Http(format!("{}: {}", url, e)))?;
+
+    match (resp.code.as_str(), resp.data) {
+        ("200000", Some(bullet)) => Ok(bullet),
+        (code, _) => Err(APIError::Http(format!("{}: code {} {}", url, code, resp.msg.unwrap_or_default()))),
+    }
+}
+
+/// Market data client, or private account client when given credentials. Every received
+/// message is parsed and passed to the channel returned by `spawn`; dropped connections are
+/// reestablished with exponential backoff.
+pub struct KucoinClient {
+    rest_url: String,
+    topics: Vec<String>,
+    credentials: Option<Credentials>,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    ack_timeout: Duration,
+}
+
+impl KucoinClient {
+    pub fn new(rest_url: &str, topics: Vec<String>) -> Self {
+        KucoinClient {
+            rest_url: rest_url.to_string(),
+            topics: topics,
+            credentials: None,
+            min_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            ack_timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn with_backoff(mut self, min_backoff: Duration, max_backoff: Duration) -> Self {
+        self.min_backoff = min_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Connects with a `bullet-private` token and subscribes to every topic as private.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Connections with subscriptions unacknowledged for this long are dropped and reopened.
+    pub fn with_ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+
+    pub fn spawn(self) -> Receiver<KucoinWebsocketMsg> {
+        let frames = self.spawn_frames();
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            for (_, msg) in frames {
+                if tx.send(msg).is_err() {
+                    return;
+                }
+            }
+        });
+
+        rx
+    }
+
+    /// Like `spawn`, with each message next to the text it was parsed from, empty for
+    /// binary frames.
+    pub fn spawn_frames(self) -> Receiver<(String, KucoinWebsocketMsg)> {
+        let (tx, rx) = channel();
+        thread::spawn(move || self.run(tx));
+        rx
+    }
+
+    fn run(&self, tx: ThreadSender<(String, KucoinWebsocketMsg)>) {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut backoff = self.min_backoff;
+
+        loop {
+            let welcomed = Arc::new(AtomicBool::new(false));
+
+            if let Err(e) = self.connect(tx.clone(), welcomed.clone(), stopped.clone()) {
+                error!("Kucoin connection failed: {}", e);
+            }
+
+            if stopped.load(Ordering::SeqCst) {
+                info!("Kucoin receiver dropped, stop client");
+                return;
+            }
+
+            if welcomed.load(Ordering::SeqCst) {
+                backoff = self.min_backoff;
+            }
+
+            warn!("Kucoin connection closed, reconnect in {:?}", backoff);
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, self.max_backoff);
+        }
+    }
+
+    fn connect(&self, tx: ThreadSender<(String, KucoinWebsocketMsg)>, welcomed: Arc<AtomicBool>, stopped: Arc<AtomicBool>) -> std::result::Result<(), APIError> {
+        let bullet = match self.credentials {
+            Some(ref credentials) => fetch_private_bullet(self.rest_url.as_str(), credentials)?,
+            None => fetch_bullet(self.rest_url.as_str())?,
+        };
+
+        let server = match bullet.instance_servers.first() {
+            Some(server) => server.clone(),
+            None => return Err(APIError::Other("Bullet has no instance servers".to_string()))
+        };
+
+        let url = format!("{}?token={}&connectId={}", server.endpoint, bullet.token, next_id());
+
+        ws::connect(url, |out| Connection {
+            out: out,
+            topics: self.topics.clone(),
+            private: self.credentials.is_some(),
+            server: server.clone(),
+            tx: tx.clone(),
+            pending: PendingAcks::new(self.ack_timeout),
+            awaiting_pong: false,
+            welcomed: welcomed.clone(),
+            stopped: stopped.clone(),
+        })?;
+
+        Ok(())
+    }
+}
+
+struct Connection {
+    out: Sender,
+    topics: Vec<String>,
+    private: bool,
+    server: InstanceServer,
+    tx: ThreadSender<(String, KucoinWebsocketMsg)>,
+    pending: PendingAcks,
+    awaiting_pong: bool,
+    welcomed: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Connection {
+    fn send(&mut self, command: Command) -> Result<()> {
+        let frame = command.build();
+        self.pending.track(&frame);
+        self.out.send(frame.text)
+    }
+
+    fn subscribe(&mut self) -> Result<()> {
+        for topic in self.topics.clone() {
+            let command = match self.private {
+                true => Command::subscribe_private(topic.as_str()),
+                false => Command::subscribe(topic.as_str()),
+            };
+            self.send(command)?;
+        }
+
+        if !self.pending.is_empty() {
+            self.out.timeout(self.pending.get_timeout().as_millis() as u64, ACK_CHECK)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Handler for Connection {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        info!("Connected to {}", self.server.endpoint);
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let (text, msg) = match msg {
+            Message::Text(text) => {
+                let msg = parse_text(text.as_str());
+                (text, msg)
+            }
+            binary => (String::new(), parse_message(binary)),
+        };
+
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("{}", e);
+                return Ok(());
+            }
+        };
+
+        match msg {
+            KucoinWebsocketMsg::WelcomeMsg(ref welcome) if welcome.r#type == "welcome" => {
+                self.welcomed.store(true, Ordering::SeqCst);
+                self.subscribe()?;
+                self.out.timeout(self.server.ping_interval, PING)?;
+            }
+            KucoinWebsocketMsg::WelcomeMsg(ref ack) if ack.r#type == "ack" => {
+                if self.pending.ack(ack.id.as_str()).is_none() {
+                    debug!("Ack for unknown request {}", ack.id);
+                }
+            }
+            KucoinWebsocketMsg::PongMsg(_) => self.awaiting_pong = false,
+            _ => {}
+        }
+
+        if self.tx.send((text, msg)).is_err() {
+            self.stopped.store(true, Ordering::SeqCst);
+            return self.out.close(CloseCode::Normal);
+        }
+
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        match event {
+            PING => {
+                self.send(Command::ping())?;
+                self.awaiting_pong = true;
+                self.out.timeout(self.server.ping_timeout, PONG_CHECK)?;
+                self.out.timeout(self.server.ping_interval, PING)
+            }
+            PONG_CHECK if self.awaiting_pong => {
+                warn!("No pong from {} in {}ms", self.server.endpoint, self.server.ping_timeout);
+                self.out.close(CloseCode::Away)
+            }
+            ACK_CHECK => {
+                let expired = self.pending.expired(Instant::now());
+                if expired.is_empty() {
+                    return match self.pending.is_empty() {
+                        true => Ok(()),
+                        false => self.out.timeout(self.pending.get_timeout().as_millis() as u64, ACK_CHECK),
+                    };
+                }
+
+                warn!("{} requests to {} were not acknowledged: {:?}", expired.len(), self.server.endpoint, expired);
+                self.out.close(CloseCode::Away)
+            }
+            _ => Ok(())
+        }
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        info!("Disconnected from {}: {:?} {}", self.server.endpoint, code, reason);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::e1::KucoinWebsocketMsg;
+    use crate::kucoin::client::KucoinClient;
+    use crate::kucoin::mock::MockExchange;
+
+    const TICKER: &str = r#"{"type":"message","topic":"/market/ticker:BTC-USDT","subject":"trade.ticker","data":{"sequence":"1545896668986","price":"0.08","size":"0.011","bestAsk":"0.08","bestAskSize":"0.18","bestBid":"0.049","bestBidSize":"0.036"}}"#;
+
+    #[test]
+    fn test_subscribe_and_receive() {
+        let exchange = MockExchange::start(vec![("/market/ticker:BTC-USDT", vec![TICKER])], false);
+        let rx = KucoinClient::new(exchange.rest_url.as_str(), vec!["/market/ticker:BTC-USDT".to_string()]).spawn();
+
+        let mut ticker = None;
+        while let Ok(msg) = rx.recv_timeout(Duration::from_secs(5)) {
+            if let KucoinWebsocketMsg::TickerMsg(msg) = msg {
+                ticker = Some(msg);
+                break;
+            }
+        }
+
+        let ticker = ticker.expect("ticker was not received");
+        assert_eq!("/market/ticker:BTC-USDT", ticker.topic.as_str());
+        assert!(exchange.received().iter().any(|frame| frame.contains("\"type\":\"subscribe\"")));
+    }
+
+    #[test]
+    fn test_ping() {
+        let exchange = MockExchange::start(vec![], false);
+        let rx = KucoinClient::new(exchange.rest_url.as_str(), vec![]).spawn();
+
+        let mut pong = false;
+        while let Ok(msg) = rx.recv_timeout(Duration::from_secs(5)) {
+            if let KucoinWebsocketMsg::PongMsg(_) = msg {
+                pong = true;
+                break;
+            }
+        }
+
+        assert!(pong);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let exchange = MockExchange::start(vec![("/market/ticker:BTC-USDT", vec![TICKER])], true);
+        let rx = KucoinClient::new(exchange.rest_url.as_str(), vec!["/market/ticker:BTC-USDT".to_string()])
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(100))
+            .spawn();
+
+        let mut tickers = 0;
+        while let Ok(msg) = rx.recv_timeout(Duration::from_secs(5)) {
+            if let KucoinWebsocketMsg::TickerMsg(_) = msg {
+                tickers += 1;
+                if tickers == 2 {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(2, tickers);
+        assert!(exchange.connections() >= 2);
+    }
+
+    #[test]
+    fn test_ack_timeout_reconnects() {
+        let exchange = MockExchange::start_with_acks(vec![], false, false);
+        let rx = KucoinClient::new(exchange.rest_url.as_str(), vec!["/market/ticker:BTC-USDT".to_string()])
+            .with_ack_timeout(Duration::from_millis(200))
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(100))
+            .spawn();
+
+        let mut welcomes = 0;
+        while let Ok(msg) = rx.recv_timeout(Duration::from_secs(5)) {
+            if let KucoinWebsocketMsg::WelcomeMsg(_) = msg {
+                welcomes += 1;
+                if welcomes == 2 {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(2, welcomes);
+        assert!(exchange.connections() >= 2);
+        assert!(exchange.received().iter().filter(|frame| frame.contains("\"type\":\"subscribe\"")).count() >= 2);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::e1::{DefaultMsg, Subscribe};
+use crate::kucoin::client::next_id;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Subscribe { topic: String, private: bool },
+    Unsubscribe { topic: String, private: bool },
+    Ping,
+}
+
+/// Serialized request ready to be sent, with the id the server answers with.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub id: String,
+    pub text: String,
+    pub command: Command,
+}
+
+impl Command {
+    pub fn subscribe(topic: &str) -> Self {
+        Command::Subscribe { topic: topic.to_string(), private: false }
+    }
+
+    /// Private topics need a connection opened with a `bullet-private` token.
+    pub fn subscribe_private(topic: &str) -> Self {
+        Command::Subscribe { topic: topic.to_string(), private: true }
+    }
+
+    pub fn unsubscribe(topic: &str) -> Self {
+        Command::Unsubscribe { topic: topic.to_string(), private: false }
+    }
+
+    pub fn unsubscribe_private(topic: &str) -> Self {
+        Command::Unsubscribe { topic: topic.to_string(), private: true }
+    }
+
+    pub fn ping() -> Self {
+        Command::Ping
+    }
+
+    /// Subscriptions are answered with an `ack`, pings with a `pong`.
+    pub fn expects_ack(&self) -> bool {
+        match self {
+            Command::Ping => false,
+            _ => true,
+        }
+    }
+
+    pub fn build(&self) -> Frame {
+        let id = next_id();
+
+        let text = match self {
+            Command::Subscribe { topic, private } => subscription(id.as_str(), "subscribe", topic, *private),
+            Command::Unsubscribe { topic, private } => subscription(id.as_str(), "unsubscribe", topic, *private),
+            Command::Ping => serde_json::to_string(&DefaultMsg { id: id.clone(), r#type: "ping".to_string() }),
+        };
+
+        Frame {
+            id: id,
+            text: text.expect("Command frames always serialize"),
+            command: self.clone(),
+        }
+    }
+}
+
+fn subscription(id: &str, r#type: &str, topic: &str, private: bool) -> serde_json::Result<String> {
+    serde_json::to_string(&Subscribe {
+        id: id.to_string(),
+        r#type: r#type.to_string(),
+        topic: topic.to_string(),
+        private_channel: private,
+        response: true,
+    })
+}
+
+/// Sent commands waiting for their `ack`.
+pub struct PendingAcks {
+    timeout: Duration,
+    pending: HashMap<String, (Command, Instant)>,
+}
+
+impl PendingAcks {
+    pub fn new(timeout: Duration) -> Self {
+        PendingAcks {
+            timeout: timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn get_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn track(&mut self, frame: &Frame) {
+        if frame.command.expects_ack() {
+            self.pending.insert(frame.id.clone(), (frame.command.clone(), Instant::now()));
+        }
+    }
+
+    /// Command acknowledged by `id`, if it was pending.
+    pub fn ack(&mut self, id: &str) -> Option<Command> {
+        self.pending.remove(id).map(|(command, _)| command)
+    }
+
+    /// Removes and returns commands sent more than `timeout` before `now`.
+    pub fn expired(&mut self, now: Instant) -> Vec<Command> {
+        let timeout = self.timeout;
+        let expired: Vec<String> = self.pending.iter()
+            .filter(|(_, (_, sent))| now.duration_since(*sent) >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        expired.into_iter().filter_map(|id| self.ack(id.as_str())).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::e1::{KucoinWebsocketMsg, parse_text};
+    use crate::kucoin::command::{Command, PendingAcks};
+
+    #[test]
+    fn test_build() {
+        let subscribe = Command::subscribe_private("/account/balance").build();
+        let unsubscribe = Command::unsubscribe("/market/ticker:BTC-USDT").build();
+        let ping = Command::ping().build();
+
+        assert_ne!(subscribe.id, unsubscribe.id);
+        assert_ne!(unsubscribe.id, ping.id);
+
+        let frame: serde_json::Value = serde_json::from_str(subscribe.text.as_str()).unwrap();
+        assert_eq!(json!({
+            "id": subscribe.id,
+            "type": "subscribe",
+            "topic": "/account/balance",
+            "privateChannel": true,
+            "response": true,
+        }), frame);
+
+        match parse_text(unsubscribe.text.as_str()) {
+            Ok(KucoinWebsocketMsg::SubscribeMsg(msg)) => assert_eq!(("unsubscribe", false), (msg.r#type.as_str(), msg.private_channel)),
+            other => panic!("{:?}", other),
+        }
+
+        match parse_text(ping.text.as_str()) {
+            Ok(KucoinWebsocketMsg::PingMsg(msg)) => assert_eq!(ping.id, msg.id),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pending_acks() {
+        let mut pending = PendingAcks::new(Duration::from_millis(100));
+        let first = Command::subscribe("/market/ticker:BTC-USDT").build();
+        let second = Command::subscribe("/market/match:BTC-USDT").build();
+
+        pending.track(&first);
+        pending.track(&second);
+        pending.track(&Command::ping().build());
+        assert_eq!(2, pending.len());
+
+        assert_eq!(Some(Command::subscribe("/market/ticker:BTC-USDT")), pending.ack(first.id.as_str()));
+        assert_eq!(None, pending.ack(first.id.as_str()));
+
+        assert!(pending.expired(Instant::now()).is_empty());
+        assert_eq!(vec![Command::subscribe("/market/match:BTC-USDT")], pending.expired(Instant::now() + Duration::from_millis(100)));
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,311 @@
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Visitor};
+
+/// Number of fractional digits kept by `Decimal`.
+pub const SCALE: u32 = 18;
+
+const ONE: i128 = 1_000_000_000_000_000_000;
+
+/// Fixed-point decimal with 18 fractional digits for exchange prices, sizes and rates.
+///
+/// Values deserialize from JSON strings or numbers and serialize as strings, so nothing is
+/// rounded through `f32`/`f64` on the way in or out.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Decimal(i128);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseDecimalError(String);
+
+impl fmt::Display for ParseDecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not a decimal: {}", self.0)
+    }
+}
+
+impl Decimal {
+    pub const ZERO: Decimal = Decimal(0);
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn abs(&self) -> Decimal {
+        Decimal(self.0.abs())
+    }
+
+    pub fn checked_add(self, other: Decimal) -> Option<Decimal> {
+        self.0.checked_add(other.0).map(Decimal)
+    }
+
+    pub fn checked_sub(self, other: Decimal) -> Option<Decimal> {
+        self.0.checked_sub(other.0).map(Decimal)
+    }
+
+    /// Splits both operands into integer and fractional parts so the intermediate products
+    /// stay inside `i128` for any realistic price times size.
+    pub fn checked_mul(self, other: Decimal) -> Option<Decimal> {
+        let (ai, af) = (self.0 / ONE, self.0 % ONE);
+        let (bi, bf) = (other.0 / ONE, other.0 % ONE);
+
+        ai.checked_mul(bi)?.checked_mul(ONE)?
+            .checked_add(ai.checked_mul(bf)?)?
+            .checked_add(af.checked_mul(bi)?)?
+            .checked_add(af.checked_mul(bf)? / ONE)
+            .map(Decimal)
+    }
+
+    /// Long division, truncating after 18 fractional digits.
+    pub fn checked_div(self, other: Decimal) -> Option<Decimal> {
+        if other.0 == 0 {
+            return None;
+        }
+
+        let negative = (self.0 < 0) != (other.0 < 0);
+        let (a, b) = (self.0.checked_abs()?, other.0.checked_abs()?);
+
+        let mut result = (a / b).checked_mul(ONE)?;
+        let mut remainder = a % b;
+        let mut unit = ONE;
+
+        while unit > 1 && remainder != 0 {
+            unit /= 10;
+            remainder = remainder.checked_mul(10)?;
+            result = result.checked_add((remainder / b) * unit)?;
+            remainder %= b;
+        }
+
+        Some(Decimal(if negative { -result } else { result }))
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        (self.0 / ONE) as f64 + (self.0 % ONE) as f64 / ONE as f64
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = ParseDecimalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseDecimalError(s.to_string());
+        let text = s.trim();
+
+        let (negative, digits) = match text.as_bytes().first() {
+            Some(b'-') => (true, &text[1..]),
+            Some(b'+') => (false, &text[1..]),
+            _ => (false, text),
+        };
+
+        let mut parts = digits.splitn(2, '.');
+        let int_part = parts.next().unwrap_or("");
+        let frac_part = parts.next().unwrap_or("");
+
+        if int_part.is_empty() && frac_part.is_empty() {
+            return Err(error());
+        }
+
+        if !int_part.bytes().chain(frac_part.bytes()).all(|b| b.is_ascii_digit()) {
+            return Err(error());
+        }
+
+        let significant = frac_part.trim_end_matches('0');
+        if significant.len() > SCALE as usize {
+            return Err(error());
+        }
+
+        let mut value: i128 = 0;
+        for b in int_part.bytes().chain(significant.bytes()) {
+            value = value.checked_mul(10).and_then(|v| v.checked_add((b - b'0') as i128)).ok_or_else(error)?;
+        }
+        for _ in significant.len()..SCALE as usize {
+            value = value.checked_mul(10).ok_or_else(error)?;
+        }
+
+        Ok(Decimal(if negative { -value } else { value }))
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let value = self.0.abs() as u128;
+        let int_part = value / ONE as u128;
+        let frac_part = value % ONE as u128;
+
+        if frac_part == 0 {
+            return write!(f, "{}{}", sign, int_part);
+        }
+
+        let frac = format!("{:018}", frac_part);
+        write!(f, "{}{}.{}", sign, int_part, frac.trim_end_matches('0'))
+    }
+}
+
+impl fmt::Debug for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Decimal({})", self)
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(value: i64) -> Self {
+        Decimal(value as i128 * ONE)
+    }
+}
+
+impl Add for Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: Decimal) -> Decimal {
+        self.checked_add(other).expect("Decimal overflow")
+    }
+}
+
+impl AddAssign for Decimal {
+    fn add_assign(&mut self, other: Decimal) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Decimal {
+    type Output = Decimal;
+
+    fn sub(self, other: Decimal) -> Decimal {
+        self.checked_sub(other).expect("Decimal overflow")
+    }
+}
+
+impl SubAssign for Decimal {
+    fn sub_assign(&mut self, other: Decimal) {
+        *self = *self - other;
+    }
+}
+
+impl Mul for Decimal {
+    type Output = Decimal;
+
+    fn mul(self, other: Decimal) -> Decimal {
+        self.checked_mul(other).expect("Decimal overflow")
+    }
+}
+
+impl Div for Decimal {
+    type Output = Decimal;
+
+    fn div(self, other: Decimal) -> Decimal {
+        self.checked_div(other).expect("Decimal division by zero or overflow")
+    }
+}
+
+impl Neg for Decimal {
+    type Output = Decimal;
+
+    fn neg(self) -> Decimal {
+        Decimal(-self.0)
+    }
+}
+
+impl Sum for Decimal {
+    fn sum<I: Iterator<Item=Decimal>>(iter: I) -> Decimal {
+        iter.fold(Decimal::ZERO, |total, value| total + value)
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+struct DecimalVisitor;
+
+impl<'de> Visitor<'de> for DecimalVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a decimal string or number")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+        value.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+        (value as i128).checked_mul(ONE).map(Decimal).ok_or_else(|| E::custom(format!("Decimal overflow: {}", value)))
+    }
+
+    /// JSON numbers with a fraction only reach us as `f64`; its shortest round-trip text is
+    /// the number that was written in the message.
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+        if !value.is_finite() {
+            return Err(E::custom(format!("Not a finite number: {}", value)));
+        }
+
+        format!("{}", value).parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::kucoin::decimal::Decimal;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!("0.08", format!("{}", d("0.08200000000000000000") - d("0.002")));
+        assert_eq!("6500", format!("{}", d("6500")));
+        assert_eq!("-0.00001", format!("{}", d("-0.00001")));
+        assert_eq!("0.5", format!("{}", d(".5")));
+        assert_eq!("0", format!("{}", d("0.000")));
+        assert!("".parse::<Decimal>().is_err());
+        assert!("1.2.3".parse::<Decimal>().is_err());
+        assert!("abc".parse::<Decimal>().is_err());
+        assert!("0.0000000000000000001".parse::<Decimal>().is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(d("0.3"), d("0.1") + d("0.2"));
+        assert_eq!(d("0.6"), d("1") - d("0.4"));
+        assert_eq!(d("2666.6"), d("6666.5") * d("0.4"));
+        assert_eq!(d("-0.02"), d("0.1") * d("-0.2"));
+        assert_eq!(d("0.333333333333333333"), d("1") / d("3"));
+        assert_eq!(d("-2.5"), d("-5") / d("2"));
+        assert_eq!(None, d("1").checked_div(Decimal::ZERO));
+        assert_eq!(d("3.5"), vec![d("1"), d("2.5")].into_iter().sum());
+        assert!(d("6500.1") > d("6500.05"));
+    }
+
+    #[test]
+    fn test_serde() {
+        let values: Vec<Decimal> = serde_json::from_str(r#"["0.08", 0.0001092, 7, -3, "1017.5"]"#).unwrap();
+        assert_eq!(vec![d("0.08"), d("0.0001092"), d("7"), d("-3"), d("1017.5")], values);
+        assert_eq!(r#"["0.08","7"]"#, serde_json::to_string(&vec![d("0.08"), d("7")]).unwrap());
+        assert!(serde_json::from_str::<Decimal>("true").is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+use crate::e1::{APIError, Level2};
+use crate::kucoin::decimal::Decimal;
+
+/// Deltas buffered while the book waits for a snapshot, the oldest are dropped first.
+pub const MAX_PENDING: usize = 1_000;
+
+/// Full book as returned by `GET /api/v1/market/orderbook/level2_100`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Level2Snapshot {
+    pub sequence: String,
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+}
+
+pub trait SnapshotSource: Send + Sync {
+    fn snapshot(&self, symbol: &str) -> Result<Level2Snapshot, APIError>;
+}
+
+pub struct RestSnapshotSource {
+    rest_url: String,
+}
+
+impl RestSnapshotSource {
+    pub fn new(rest_url: &str) -> Self {
+        RestSnapshotSource { rest_url: rest_url.trim_end_matches('/').to_string() }
+    }
+
+    pub fn get_rest_url(&self) -> &str {
+        self.rest_url.as_str()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotResp {
+    code: String,
+    data: Option<Level2Snapshot>,
+}
+
+impl SnapshotSource for RestSnapshotSource {
+    fn snapshot(&self, symbol: &str) -> Result<Level2Snapshot, APIError> {
+        let url = format!("{}/api/v1/market/orderbook/level2_100?symbol={}", self.rest_url, symbol);
+
+        let resp: SnapshotResp = reqwest::blocking::get(url.as_str())
+            .and_then(|resp| resp.json())
+            .map_err(|e| APIError::Http(format!("{}: {}", url, e)))?;
+
+        match (resp.code.as_str(), resp.data) {
+            ("200000", Some(snapshot)) => Ok(snapshot),
+            (code, _) => Err(APIError::Http(format!("{}: code {}", url, code))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BookError {
+    Gap { expected: i64, got: i64 },
+    NotSynced,
+    Crossed { bid: Decimal, ask: Decimal },
+    InvalidChange(String),
+    Snapshot(APIError),
+}
+
+impl fmt::Display for BookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BookError::Gap { expected, got } => write!(f, "Sequence gap, expected {} got {}", expected, got),
+            BookError::NotSynced => write!(f, "Book is waiting for a snapshot"),
+            BookError::Crossed { bid, ask } => write!(f, "Book is crossed, bid {} >= ask {}", bid, ask),
+            BookError::InvalidChange(e) => write!(f, "Invalid change: {}", e),
+            BookError::Snapshot(e) => write!(f, "Cannot load snapshot: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Depth {
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Bid,
+    Ask,
+}
+
+/// Local Level-2 book kept in sync from `trade.l2update` deltas. Deltas that arrive before
+/// the first snapshot, or after a sequence gap, are buffered and replayed on resync.
+pub struct OrderBook {
+    symbol: String,
+    sequence: i64,
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+    pending: VecDeque<Level2>,
+    synced: bool,
+    snapshot: Option<Receiver<Result<Level2Snapshot, String>>>,
+}
+
+impl OrderBook {
+    pub fn new(symbol: &str) -> Self {
+        OrderBook {
+            symbol: symbol.to_string(),
+            sequence: 0,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            pending: VecDeque::new(),
+            synced: false,
+            snapshot: None,
+        }
+    }
+
+    pub fn get_symbol(&self) -> &str {
+        self.symbol.as_str()
+    }
+
+    pub fn get_sequence(&self) -> i64 {
+        self.sequence
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Applies a delta. When the book is behind the delta is buffered and a snapshot is
+    /// fetched from `source` in the background, one request at a time, and loaded by a later
+    /// `update` or `poll_snapshot`.
+    pub fn update(&mut self, delta: Level2, source: &Arc<dyn SnapshotSource>) -> Result<(), BookError> {
+        self.poll_snapshot()?;
+
+        match self.apply(delta) {
+            Err(BookError::Gap { expected, got }) => {
+                warn!("{} sequence gap, expected {} got {}, resync", self.symbol, expected, got);
+                self.request_snapshot(source);
+                Ok(())
+            }
+            Err(BookError::NotSynced) => {
+                self.request_snapshot(source);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn request_snapshot(&mut self, source: &Arc<dyn SnapshotSource>) {
+        if self.snapshot.is_some() {
+            return;
+        }
+
+        let (tx, rx) = channel();
+        let source = source.clone();
+        let symbol = self.symbol.clone();
+        thread::spawn(move || tx.send(source.snapshot(symbol.as_str()).map_err(|e| e.to_string())));
+
+        self.snapshot = Some(rx);
+    }
+
+    /// Loads the snapshot requested by `update` once it has arrived, returns whether the
+    /// book is synced.
+    pub fn poll_snapshot(&mut self) -> Result<bool, BookError> {
+        let result = match self.snapshot.as_ref().map(|rx| rx.try_recv()) {
+            Some(Ok(result)) => result,
+            Some(Err(TryRecvError::Disconnected)) => Err("Snapshot request died".to_string()),
+            Some(Err(TryRecvError::Empty)) | None => return Ok(self.synced),
+        };
+        self.snapshot = None;
+
+        match result {
+            Ok(snapshot) => self.load_snapshot(snapshot).map(|_| self.synced),
+            Err(e) => Err(BookError::Snapshot(APIError::Other(e))),
+        }
+    }
+
+    fn buffer(&mut self, delta: Level2) {
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(delta);
+    }
+
+    /// Applies a delta without fetching snapshots. Deltas that cannot be applied yet are kept
+    /// for the next `load_snapshot`.
+    pub fn apply(&mut self, delta: Level2) -> Result<(), BookError> {
+        if !self.synced {
+            self.buffer(delta);
+            return Err(BookError::NotSynced);
+        }
+
+        if delta.sequence_end <= self.sequence {
+            return Ok(());
+        }
+
+        if delta.sequence_start > self.sequence + 1 {
+            let expected = self.sequence + 1;
+            let got = delta.sequence_start;
+            self.synced = false;
+            self.buffer(delta);
+            return Err(BookError::Gap { expected: expected, got: got });
+        }
+
+        let mut changes = Vec::new();
+        for (side, levels) in vec![(Side::Bid, &delta.changes.bids), (Side::Ask, &delta.changes.asks)] {
+            for change in levels {
+                match change.2.parse::<i64>() {
+                    Ok(sequence) => changes.push((sequence, side, change.0, change.1)),
+                    Err(_) => return Err(BookError::InvalidChange(format!("Sequence is not integer: {:?}", change.2)))
+                }
+            }
+        }
+        changes.sort_by_key(|(sequence, _, _, _)| *sequence);
+
+        for (sequence, side, price, size) in changes {
+            if sequence > self.sequence {
+                self.set_level(side, price, size)?;
+            }
+        }
+        self.sequence = delta.sequence_end;
+
+        Ok(())
+    }
+
+    /// Loads a snapshot right away, discarding one still requested by `update`.
+    pub fn resync(&mut self, source: &dyn SnapshotSource) -> Result<(), BookError> {
+        self.snapshot = None;
+        let snapshot = source.snapshot(self.symbol.as_str()).map_err(BookError::Snapshot)?;
+        self.load_snapshot(snapshot)
+    }
+
+    /// Replaces the book with `snapshot` and replays buffered deltas newer than it.
+    pub fn load_snapshot(&mut self, snapshot: Level2Snapshot) -> Result<(), BookError> {
+        let sequence = match snapshot.sequence.parse::<i64>() {
+            Ok(sequence) => sequence,
+            Err(_) => return Err(BookError::InvalidChange(format!("Snapshot sequence is not integer: {:?}", snapshot.sequence)))
+        };
+
+        self.bids.clear();
+        self.asks.clear();
+        for (side, levels) in vec![(Side::Bid, &snapshot.bids), (Side::Ask, &snapshot.asks)] {
+            for (price, size) in levels {
+                self.set_level(side, *price, *size)?;
+            }
+        }
+
+        self.sequence = sequence;
+        self.synced = true;
+
+        let mut pending: Vec<Level2> = self.pending.drain(..).collect();
+        pending.sort_by_key(|delta| delta.sequence_start);
+
+        let mut result = Ok(());
+        for delta in pending {
+            if result.is_err() {
+                self.pending.push_back(delta);
+            } else {
+                result = self.apply(delta);
+            }
+        }
+
+        result
+    }
+
+    fn set_level(&mut self, side: Side, price: Decimal, size: Decimal) -> Result<(), BookError> {
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+
+        if size.is_zero() {
+            levels.remove(&price);
+        } else if size.is_positive() {
+            levels.insert(price, size);
+        } else {
+            return Err(BookError::InvalidChange(format!("Size is negative: {}", size)));
+        }
+
+        Ok(())
+    }
+
+    pub fn best_bid(&self) -> Option<(Decimal, Decimal)> {
+        self.bids.iter().next_back().map(|(price, size)| (*price, *size))
+    }
+
+    pub fn best_ask(&self) -> Option<(Decimal, Decimal)> {
+        self.asks.iter().next().map(|(price, size)| (*price, *size))
+    }
+
+    /// Best `n` levels of each side, best first.
+    pub fn depth(&self, n: usize) -> Depth {
+        Depth {
+            bids: self.bids.iter().rev().take(n).map(|(price, size)| (*price, *size)).collect(),
+            asks: self.asks.iter().take(n).map(|(price, size)| (*price, *size)).collect(),
+        }
+    }
+
+    /// Sanity checks that do not need an exchange checksum.
+    pub fn check(&self) -> Result<(), BookError> {
+        if !self.synced {
+            return Err(BookError::NotSynced);
+        }
+
+        if let (Some((bid, _)), Some((ask, _))) = (self.best_bid(), self.best_ask()) {
+            if bid >= ask {
+                return Err(BookError::Crossed { bid: bid, ask: ask });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::e1::{APIError, KucoinWebsocketMsg, Level2, parse_text};
+    use crate::kucoin::decimal::Decimal;
+    use crate::kucoin::level2::{BookError, Level2Snapshot, OrderBook, SnapshotSource, MAX_PENDING};
+
+    struct FixtureSource {
+        snapshots: Mutex<Vec<&'static str>>,
+    }
+
+    impl SnapshotSource for FixtureSource {
+        fn snapshot(&self, _: &str) -> Result<Level2Snapshot, APIError> {
+            let snapshot = self.snapshots.lock().unwrap().remove(0);
+            Ok(serde_json::from_str(snapshot)?)
+        }
+    }
+
+    /// Counts requests and answers each one only when the gate opens.
+    struct GatedSource {
+        calls: AtomicUsize,
+        gate: Mutex<Receiver<()>>,
+    }
+
+    impl SnapshotSource for GatedSource {
+        fn snapshot(&self, _: &str) -> Result<Level2Snapshot, APIError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.gate.lock().unwrap().recv().unwrap();
+            Ok(serde_json::from_str(include_str!("../../fixtures/kucoin/level2_snapshot.json"))?)
+        }
+    }
+
+    fn fixtures(snapshots: Vec<&'static str>) -> Arc<FixtureSource> {
+        Arc::new(FixtureSource { snapshots: Mutex::new(snapshots) })
+    }
+
+    /// Waits for the snapshot requested in the background to be loaded.
+    fn settle(book: &mut OrderBook) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while !book.poll_snapshot().unwrap() {
+            assert!(Instant::now() < deadline, "snapshot was not loaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn updates() -> Vec<Level2> {
+        let messages: Vec<serde_json::Value> = serde_json::from_str(include_str!("../../fixtures/kucoin/level2_updates.json")).unwrap();
+
+        messages.into_iter()
+            .map(|msg| match parse_text(msg.to_string().as_str()) {
+                Ok(KucoinWebsocketMsg::OrderBookMsg(msg)) => msg.data,
+                other => panic!("{:?}", other),
+            })
+            .collect()
+    }
+
+    fn pair(price: &str, size: &str) -> Option<(Decimal, Decimal)> {
+        Some((price.parse().unwrap(), size.parse().unwrap()))
+    }
+
+    #[test]
+    fn test_apply_updates() {
+        let source: Arc<dyn SnapshotSource> = fixtures(vec![include_str!("../../fixtures/kucoin/level2_snapshot.json")]);
+        let mut book = OrderBook::new("BTC-USDT");
+
+        for delta in updates() {
+            book.update(delta, &source).unwrap();
+        }
+        settle(&mut book);
+
+        assert_eq!(105, book.get_sequence());
+        assert_eq!(pair("6500.1", "0.7"), book.best_bid());
+        assert_eq!(pair("6500.8", "1"), book.best_ask());
+
+        let depth = book.depth(2);
+        assert_eq!(vec![pair("6500.1", "0.7").unwrap(), pair("6500", "1").unwrap()], depth.bids);
+        assert_eq!(vec![pair("6500.8", "1").unwrap(), pair("6501", "1.5").unwrap()], depth.asks);
+        assert!(book.check().is_ok());
+    }
+
+    #[test]
+    fn test_gap_resync() {
+        let snapshots = fixtures(vec![
+            include_str!("../../fixtures/kucoin/level2_snapshot.json"),
+            include_str!("../../fixtures/kucoin/level2_snapshot_resync.json"),
+        ]);
+        let source: Arc<dyn SnapshotSource> = snapshots.clone();
+        let mut book = OrderBook::new("BTC-USDT");
+        let mut updates = updates();
+        updates.remove(2);
+
+        let mut updates = updates.into_iter();
+        book.update(updates.next().unwrap(), &source).unwrap();
+        settle(&mut book);
+        book.update(updates.next().unwrap(), &source).unwrap();
+
+        match book.apply(updates.next().unwrap()) {
+            Err(BookError::Gap { expected: 103, got: 104 }) => {}
+            other => panic!("{:?}", other),
+        }
+        assert!(!book.is_synced());
+
+        book.resync(&*source).unwrap();
+
+        assert_eq!(105, book.get_sequence());
+        assert_eq!(pair("6500.1", "0.7"), book.best_bid());
+        assert_eq!(pair("6500.8", "1"), book.best_ask());
+        assert!(snapshots.snapshots.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_one_snapshot_request_while_unsynced() {
+        let (open, gate) = channel();
+        let gated = Arc::new(GatedSource { calls: AtomicUsize::new(0), gate: Mutex::new(gate) });
+        let source: Arc<dyn SnapshotSource> = gated.clone();
+        let mut book = OrderBook::new("BTC-USDT");
+        let delta = updates().remove(0);
+
+        for _ in 0..MAX_PENDING + 10 {
+            book.update(delta.clone(), &source).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(1, gated.calls.load(Ordering::SeqCst));
+        assert_eq!(MAX_PENDING, book.pending.len());
+        assert!(!book.is_synced());
+
+        open.send(()).unwrap();
+        settle(&mut book);
+        assert!(book.pending.is_empty());
+    }
+
+    #[test]
+    fn test_check_crossed() {
+        let mut book = OrderBook::new("BTC-USDT");
+        let snapshot: Level2Snapshot = serde_json::from_str(r#"{"sequence":"1","bids":[["10","1"]],"asks":[["9","1"]]}"#).unwrap();
+        book.load_snapshot(snapshot).unwrap();
+
+        match book.check() {
+            Err(BookError::Crossed { .. }) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,546 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::e1::{APIError, KucoinWebsocketMsg};
+use crate::kucoin::decimal::Decimal;
+use crate::kucoin::level2::{BookError, RestSnapshotSource};
+
+/// Full book as returned by `GET /api/v1/market/orderbook/level3`. Orders are
+/// `[orderId, price, size, time]` in time priority.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Level3Snapshot {
+    pub sequence: String,
+    pub bids: Vec<(String, Decimal, Decimal, i64)>,
+    pub asks: Vec<(String, Decimal, Decimal, i64)>,
+}
+
+pub trait Level3SnapshotSource {
+    fn level3_snapshot(&self, symbol: &str) -> Result<Level3Snapshot, APIError>;
+}
+
+#[derive(Debug, Deserialize)]
+struct Level3SnapshotResp {
+    code: String,
+    data: Option<Level3Snapshot>,
+}
+
+impl Level3SnapshotSource for RestSnapshotSource {
+    fn level3_snapshot(&self, symbol: &str) -> Result<Level3Snapshot, APIError> {
+        let url = format!("{}/api/v1/market/orderbook/level3?symbol={}", self.get_rest_url(), symbol);
+
+        let resp: Level3SnapshotResp = reqwest::blocking::get(url.as_str())
+            .and_then(|resp| resp.json())
+            .map_err(|e| APIError::Http(format!("{}: {}", url, e)))?;
+
+        match (resp.code.as_str(), resp.data) {
+            ("200000", Some(snapshot)) => Ok(snapshot),
+            (code, _) => Err(APIError::Http(format!("{}: code {}", url, code))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub order_id: String,
+    pub side: String,
+    pub price: Decimal,
+    pub size: Decimal,
+    pub time: String,
+}
+
+/// Trade derived from a `trade.l3match` event. `side` is the taker side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub trade_id: String,
+    pub symbol: String,
+    pub sequence: i64,
+    pub side: String,
+    pub price: Decimal,
+    pub size: Decimal,
+    pub maker_order_id: String,
+    pub taker_order_id: String,
+    pub time: String,
+}
+
+/// Full depth book tracking every resting order from the `/market/level3` stream. Events that
+/// arrive before the first snapshot, or after a sequence gap, are buffered and replayed on resync.
+pub struct Level3Book {
+    symbol: String,
+    sequence: i64,
+    orders: HashMap<String, Order>,
+    received: HashMap<String, Option<Decimal>>,
+    bids: BTreeMap<Decimal, VecDeque<String>>,
+    asks: BTreeMap<Decimal, VecDeque<String>>,
+    pending: Vec<(i64, KucoinWebsocketMsg)>,
+    synced: bool,
+}
+
+impl Level3Book {
+    pub fn new(symbol: &str) -> Self {
+        Level3Book {
+            symbol: symbol.to_string(),
+            sequence: 0,
+            orders: HashMap::new(),
+            received: HashMap::new(),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            pending: Vec::new(),
+            synced: false,
+        }
+    }
+
+    pub fn get_sequence(&self) -> i64 {
+        self.sequence
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    pub fn get_order(&self, order_id: &str) -> Option<&Order> {
+        self.orders.get(order_id)
+    }
+
+    pub fn order_count(&self) -> usize {
+        self.orders.len()
+    }
+
+    /// Applies an event, resyncing from `source` when the book is behind. Returns the trades
+    /// of every event applied, including buffered ones replayed after a resync.
+    pub fn update(&mut self, msg: &KucoinWebsocketMsg, source: &dyn Level3SnapshotSource) -> Result<Vec<Trade>, BookError> {
+        match self.apply(msg) {
+            Ok(trade) => Ok(trade.into_iter().collect()),
+            Err(BookError::Gap { expected, got }) => {
+                warn!("{} sequence gap, expected {} got {}, resync", self.symbol, expected, got);
+                self.resync(source)
+            }
+            Err(BookError::NotSynced) => self.resync(source),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Applies one level 3 event without fetching snapshots. Events already applied are
+    /// skipped, events that cannot be applied yet are kept for the next `load_snapshot`.
+    /// Invalid events are rejected before the book is touched.
+    pub fn apply(&mut self, msg: &KucoinWebsocketMsg) -> Result<Option<Trade>, BookError> {
+        let sequence = match msg {
+            KucoinWebsocketMsg::Level3ReceivedMsg(msg) => parse_sequence(msg.data.sequence.as_str())?,
+            KucoinWebsocketMsg::Level3OpenMsg(msg) => parse_sequence(msg.data.sequence.as_str())?,
+            KucoinWebsocketMsg::Level3MatchMsg(msg) => parse_sequence(msg.data.sequence.as_str())?,
+            KucoinWebsocketMsg::Level3DoneMsg(msg) => parse_sequence(msg.data.sequence.as_str())?,
+            KucoinWebsocketMsg::Level3ChangeMsg(msg) => parse_sequence(msg.data.sequence.as_str())?,
+            KucoinWebsocketMsg::StopOrderMsg(msg) => parse_sequence(msg.data.sequence.as_str())?,
+            _ => return Ok(None),
+        };
+
+        if !self.synced {
+            self.pending.push((sequence, msg.clone()));
+            return Err(BookError::NotSynced);
+        }
+
+        if sequence <= self.sequence {
+            return Ok(None);
+        }
+
+        if sequence != self.sequence + 1 {
+            let expected = self.sequence + 1;
+            self.synced = false;
+            self.pending.push((sequence, msg.clone()));
+            return Err(BookError::Gap { expected: expected, got: sequence });
+        }
+
+        self.validate(msg)?;
+
+        let trade = match msg {
+            KucoinWebsocketMsg::Level3ReceivedMsg(msg) => {
+                self.received.insert(msg.data.order_id.clone(), msg.data.price);
+                None
+            }
+            KucoinWebsocketMsg::Level3OpenMsg(msg) => {
+                self.received.remove(&msg.data.order_id);
+                self.open(Order {
+                    order_id: msg.data.order_id.clone(),
+                    side: msg.data.side.clone(),
+                    price: msg.data.price,
+                    size: msg.data.size,
+                    time: msg.data.time.clone(),
+                });
+                None
+            }
+            KucoinWebsocketMsg::Level3MatchMsg(msg) => {
+                let size = msg.data.size;
+
+                match self.orders.get_mut(&msg.data.maker_order_id) {
+                    Some(maker) => maker.size -= size,
+                    None => warn!("{} match for unknown maker order {}", self.symbol, msg.data.maker_order_id),
+                }
+
+                Some(Trade {
+                    trade_id: msg.data.trade_id.clone(),
+                    symbol: msg.data.symbol.clone(),
+                    sequence: sequence,
+                    side: msg.data.side.clone(),
+                    price: msg.data.price,
+                    size: size,
+                    maker_order_id: msg.data.maker_order_id.clone(),
+                    taker_order_id: msg.data.taker_order_id.clone(),
+                    time: msg.data.time.clone(),
+                })
+            }
+            KucoinWebsocketMsg::Level3DoneMsg(msg) => {
+                self.received.remove(&msg.data.order_id);
+                self.remove(msg.data.order_id.as_str());
+                None
+            }
+            KucoinWebsocketMsg::Level3ChangeMsg(msg) => {
+                match self.orders.get_mut(&msg.data.order_id) {
+                    Some(order) => order.size = msg.data.new_size,
+                    None => warn!("{} change for unknown order {}", self.symbol, msg.data.order_id),
+                }
+                None
+            }
+            _ => None,
+        };
+
+        self.sequence = sequence;
+
+        Ok(trade)
+    }
+
+    fn validate(&self, msg: &KucoinWebsocketMsg) -> Result<(), BookError> {
+        match msg {
+            KucoinWebsocketMsg::Level3ReceivedMsg(msg) if self.orders.contains_key(&msg.data.order_id) => {
+                Err(BookError::InvalidChange(format!("Order {} received while resting", msg.data.order_id)))
+            }
+            KucoinWebsocketMsg::Level3OpenMsg(msg) => {
+                validate_side(msg.data.side.as_str())?;
+                validate_size(msg.data.size)?;
+
+                if self.orders.contains_key(&msg.data.order_id) {
+                    return Err(BookError::InvalidChange(format!("Order {} is already open", msg.data.order_id)));
+                }
+
+                // Orders received before the snapshot open without a known price
+                match self.received.get(&msg.data.order_id) {
+                    Some(Some(price)) if *price != msg.data.price => Err(BookError::InvalidChange(
+                        format!("Order {} opens at {} but was received at {}", msg.data.order_id, msg.data.price, price)
+                    )),
+                    _ => Ok(())
+                }
+            }
+            KucoinWebsocketMsg::Level3MatchMsg(msg) => {
+                validate_size(msg.data.size)?;
+
+                match self.orders.get(&msg.data.maker_order_id) {
+                    Some(maker) if msg.data.size > maker.size => Err(BookError::InvalidChange(
+                        format!("Match of {} exceeds maker order {} of {}", msg.data.size, maker.order_id, maker.size)
+                    )),
+                    _ => Ok(())
+                }
+            }
+            KucoinWebsocketMsg::Level3ChangeMsg(msg) => validate_size(msg.data.new_size),
+            _ => Ok(())
+        }
+    }
+
+    pub fn resync(&mut self, source: &dyn Level3SnapshotSource) -> Result<Vec<Trade>, BookError> {
+        let snapshot = source.level3_snapshot(self.symbol.as_str()).map_err(BookError::Snapshot)?;
+        self.load_snapshot(snapshot)
+    }
+
+    /// Replaces the book with `snapshot` and replays buffered events newer than it.
+    pub fn load_snapshot(&mut self, snapshot: Level3Snapshot) -> Result<Vec<Trade>, BookError> {
+        let sequence = match snapshot.sequence.parse::<i64>() {
+            Ok(sequence) => sequence,
+            Err(_) => return Err(BookError::InvalidChange(format!("Snapshot sequence is not integer: {:?}", snapshot.sequence)))
+        };
+
+        for (_, _, size, _) in snapshot.bids.iter().chain(snapshot.asks.iter()) {
+            validate_size(*size)?;
+        }
+
+        self.orders.clear();
+        self.received.clear();
+        self.bids.clear();
+        self.asks.clear();
+        for (side, orders) in vec![("buy", snapshot.bids), ("sell", snapshot.asks)] {
+            for (order_id, price, size, time) in orders {
+                self.open(Order {
+                    order_id: order_id,
+                    side: side.to_string(),
+                    price: price,
+                    size: size,
+                    time: time.to_string(),
+                });
+            }
+        }
+
+        self.sequence = sequence;
+        self.synced = true;
+
+        let mut pending: Vec<(i64, KucoinWebsocketMsg)> = self.pending.drain(..).collect();
+        pending.sort_by_key(|(sequence, _)| *sequence);
+
+        let mut trades = Vec::new();
+        let mut result = Ok(());
+        for (sequence, msg) in pending {
+            if result.is_err() {
+                self.pending.push((sequence, msg));
+                continue;
+            }
+
+            match self.apply(&msg) {
+                Ok(trade) => trades.extend(trade),
+                Err(e) => result = Err(e),
+            }
+        }
+
+        result.map(|_| trades)
+    }
+
+    fn open(&mut self, order: Order) {
+        let levels = match order.side.as_str() {
+            "buy" => &mut self.bids,
+            _ => &mut self.asks,
+        };
+
+        levels.entry(order.price).or_insert_with(VecDeque::new).push_back(order.order_id.clone());
+        self.orders.insert(order.order_id.clone(), order);
+    }
+
+    fn remove(&mut self, order_id: &str) {
+        let order = match self.orders.remove(order_id) {
+            Some(order) => order,
+            None => return
+        };
+
+        let levels = match order.side.as_str() {
+            "buy" => &mut self.bids,
+            _ => &mut self.asks,
+        };
+
+        let empty = match levels.get_mut(&order.price) {
+            Some(level) => {
+                level.retain(|id| id != order_id);
+                level.is_empty()
+            }
+            None => false
+        };
+
+        if empty {
+            levels.remove(&order.price);
+        }
+    }
+
+    fn level_size(&self, ids: &VecDeque<String>) -> Decimal {
+        ids.iter().filter_map(|id| self.orders.get(id)).map(|order| order.size).sum()
+    }
+
+    /// Best bid price with the total size resting there.
+    pub fn best_bid(&self) -> Option<(Decimal, Decimal)> {
+        self.bids.iter().next_back().map(|(price, ids)| (*price, self.level_size(ids)))
+    }
+
+    pub fn best_ask(&self) -> Option<(Decimal, Decimal)> {
+        self.asks.iter().next().map(|(price, ids)| (*price, self.level_size(ids)))
+    }
+
+    /// Resting orders at `price` in time priority.
+    pub fn orders_at(&self, side: &str, price: Decimal) -> Vec<&Order> {
+        let levels = match side {
+            "buy" => &self.bids,
+            _ => &self.asks,
+        };
+
+        match levels.get(&price) {
+            Some(ids) => ids.iter().filter_map(|id| self.orders.get(id)).collect(),
+            None => vec![],
+        }
+    }
+}
+
+fn parse_sequence(sequence: &str) -> Result<i64, BookError> {
+    sequence.parse::<i64>().map_err(|_| BookError::InvalidChange(format!("Sequence is not integer: {:?}", sequence)))
+}
+
+fn validate_side(side: &str) -> Result<(), BookError> {
+    match side {
+        "buy" | "sell" => Ok(()),
+        side => Err(BookError::InvalidChange(format!("Unknown side {:?}", side)))
+    }
+}
+
+fn validate_size(size: Decimal) -> Result<(), BookError> {
+    match size.is_negative() {
+        true => Err(BookError::InvalidChange(format!("Size is negative: {}", size))),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use crate::e1::{APIError, KucoinWebsocketMsg, parse_text};
+    use crate::kucoin::decimal::Decimal;
+    use crate::kucoin::level2::BookError;
+    use crate::kucoin::level3::{Level3Book, Level3Snapshot, Level3SnapshotSource};
+
+    struct FixtureSource {
+        snapshots: RefCell<Vec<&'static str>>,
+    }
+
+    impl Level3SnapshotSource for FixtureSource {
+        fn level3_snapshot(&self, _: &str) -> Result<Level3Snapshot, APIError> {
+            let snapshot = self.snapshots.borrow_mut().remove(0);
+            Ok(serde_json::from_str(snapshot)?)
+        }
+    }
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn events() -> Vec<KucoinWebsocketMsg> {
+        let messages: Vec<serde_json::Value> = serde_json::from_str(include_str!("../../fixtures/kucoin/level3_sequence.json")).unwrap();
+        messages.into_iter().map(|msg| parse_text(msg.to_string().as_str()).unwrap()).collect()
+    }
+
+    fn synced_book(sequence: &str) -> Level3Book {
+        let mut book = Level3Book::new("BTC-USDT");
+        let snapshot: Level3Snapshot = serde_json::from_str(format!(r#"{{"sequence":"{}","bids":[],"asks":[]}}"#, sequence).as_str()).unwrap();
+        book.load_snapshot(snapshot).unwrap();
+        book
+    }
+
+    #[test]
+    fn test_apply_sequence() {
+        let source = FixtureSource { snapshots: RefCell::new(vec![include_str!("../../fixtures/kucoin/level3_snapshot.json")]) };
+        let mut book = Level3Book::new("BTC-USDT");
+        let mut trades = Vec::new();
+
+        for event in events() {
+            trades.extend(book.update(&event, &source).unwrap());
+        }
+
+        assert_eq!(211, book.get_sequence());
+        assert_eq!(3, book.order_count());
+        assert_eq!(Some((d("3.9"), d("3"))), book.best_bid());
+        assert_eq!(Some((d("4.5"), d("1"))), book.best_ask());
+        assert_eq!(d("3.5"), book.get_order("5c24c72503aa6772d55b3780").unwrap().price);
+        assert!(book.get_order("5c24c72503aa6772d55b378a").is_none());
+
+        assert_eq!(2, trades.len());
+        assert_eq!((d("4"), d("0.4"), "5c24c72503aa6772d55b378a"), (trades[0].price, trades[0].size, trades[0].maker_order_id.as_str()));
+        assert_eq!((d("4.5"), d("1"), "5c24c72503aa6772d55b378b"), (trades[1].price, trades[1].size, trades[1].maker_order_id.as_str()));
+        assert_eq!("buy", trades[1].side.as_str());
+        assert!(source.snapshots.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_buffer_until_snapshot() {
+        let mut book = Level3Book::new("BTC-USDT");
+        let events = events();
+
+        for event in &events[..8] {
+            match book.apply(event) {
+                Err(BookError::NotSynced) => {}
+                other => panic!("{:?}", other),
+            }
+        }
+        assert_eq!(0, book.order_count());
+
+        let snapshot: Level3Snapshot = serde_json::from_str(include_str!("../../fixtures/kucoin/level3_snapshot.json")).unwrap();
+        let trades = book.load_snapshot(snapshot).unwrap();
+
+        assert!(book.is_synced());
+        assert_eq!(207, book.get_sequence());
+        assert_eq!(1, trades.len());
+        assert_eq!(d("0.6"), book.get_order("5c24c72503aa6772d55b378a").unwrap().size);
+    }
+
+    #[test]
+    fn test_skip_applied_events() {
+        let mut book = synced_book("199");
+        let events = events();
+
+        for event in &events[..8] {
+            book.apply(event).unwrap();
+        }
+
+        assert_eq!(None, book.apply(&events[7]).unwrap());
+        assert_eq!(207, book.get_sequence());
+    }
+
+    #[test]
+    fn test_gap() {
+        let mut book = synced_book("199");
+        let events = events();
+
+        for event in &events[..4] {
+            book.apply(event).unwrap();
+        }
+
+        match book.apply(&events[5]) {
+            Err(BookError::Gap { expected: 204, got: 205 }) => {}
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(203, book.get_sequence());
+        assert!(!book.is_synced());
+    }
+
+    #[test]
+    fn test_invalid_event_leaves_book() {
+        let mut book = synced_book("199");
+        let events = events();
+        book.apply(&events[0]).unwrap();
+
+        let mut messages: Vec<serde_json::Value> = serde_json::from_str(include_str!("../../fixtures/kucoin/level3_sequence.json")).unwrap();
+        let mut open = messages.remove(1);
+        open["data"]["side"] = "up".into();
+
+        match book.apply(&parse_text(open.to_string().as_str()).unwrap()) {
+            Err(BookError::InvalidChange(_)) => {}
+            other => panic!("{:?}", other),
+        }
+
+        assert_eq!(200, book.get_sequence());
+        assert!(book.received.contains_key("5c24c72503aa6772d55b378a"));
+        assert!(book.apply(&events[1]).is_ok());
+        assert_eq!(1, book.order_count());
+    }
+
+    #[test]
+    fn test_reject_duplicate_open() {
+        let mut book = synced_book("199");
+        let events = events();
+        book.apply(&events[0]).unwrap();
+        book.apply(&events[1]).unwrap();
+
+        let messages: Vec<serde_json::Value> = serde_json::from_str(include_str!("../../fixtures/kucoin/level3_sequence.json")).unwrap();
+        let mut duplicate = messages[1].clone();
+        duplicate["data"]["sequence"] = "202".into();
+
+        match book.apply(&parse_text(duplicate.to_string().as_str()).unwrap()) {
+            Err(BookError::InvalidChange(_)) => {}
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(201, book.get_sequence());
+        assert_eq!(1, book.order_count());
+    }
+
+    #[test]
+    fn test_reject_open_at_other_price() {
+        let mut book = synced_book("199");
+        book.apply(&events()[0]).unwrap();
+
+        let messages: Vec<serde_json::Value> = serde_json::from_str(include_str!("../../fixtures/kucoin/level3_sequence.json")).unwrap();
+        let mut open = messages[1].clone();
+        open["data"]["price"] = "5".into();
+
+        match book.apply(&parse_text(open.to_string().as_str()).unwrap()) {
+            Err(BookError::InvalidChange(_)) => {}
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(0, book.order_count());
+    }
+}
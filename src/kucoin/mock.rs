@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use serde_json::Value;
+use ws::{CloseCode, Handler, Handshake, Message, Result, Sender};
+
+/// Local stand-in for the Kucoin bullet endpoints and websocket server. Frames scripted for a
+/// topic are sent right after its subscription is acknowledged.
+pub struct MockExchange {
+    pub rest_url: String,
+    received: Arc<Mutex<Vec<String>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl MockExchange {
+    pub fn start(script: Vec<(&str, Vec<&str>)>, close_after_script: bool) -> Self {
+        MockExchange::start_with_acks(script, close_after_script, true)
+    }
+
+    /// Like `start`, but subscriptions are never acknowledged when `ack` is false.
+    pub fn start_with_acks(script: Vec<(&str, Vec<&str>)>, close_after_script: bool, ack: bool) -> Self {
+        let script: HashMap<String, Vec<String>> = script.into_iter()
+            .map(|(topic, frames)| (topic.to_string(), frames.into_iter().map(|f| f.to_string()).collect()))
+            .collect();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let factory_received = received.clone();
+        let factory_connections = connections.clone();
+        let socket = ws::Builder::new()
+            .build(move |out| MockConnection {
+                out: out,
+                script: script.clone(),
+                close_after_script: close_after_script,
+                ack: ack,
+                received: factory_received.clone(),
+                connections: factory_connections.clone(),
+            })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let ws_url = format!("ws://{}/endpoint", socket.local_addr().unwrap());
+        thread::spawn(move || socket.run());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let rest_url = format!("http://{}", listener.local_addr().unwrap());
+        let bullet = json!({
+            "code": "200000",
+            "data": {
+                "token": "mock-token",
+                "instanceServers": [{
+                    "endpoint": ws_url,
+                    "encrypt": false,
+                    "protocol": "websocket",
+                    "pingInterval": 50,
+                    "pingTimeout": 1000,
+                }],
+            },
+        }).to_string();
+        let unauthorized = json!({"code": "400003", "msg": "KC-API-KEY not exists"}).to_string();
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let listener_requests = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    respond(stream, listener_requests.clone(), |request| {
+                        let signed = request.lines().any(|line| line.to_lowercase().starts_with("kc-api-key:"));
+
+                        match request.starts_with("POST /api/v1/bullet-private") && !signed {
+                            true => unauthorized.clone(),
+                            false => bullet.clone(),
+                        }
+                    });
+                }
+            }
+        });
+
+        MockExchange {
+            rest_url: rest_url,
+            received: received,
+            requests: requests,
+            connections: connections,
+        }
+    }
+
+    /// Frames sent by clients so far.
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Heads of the REST requests received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+fn respond<F>(mut stream: TcpStream, requests: Arc<Mutex<Vec<String>>>, body: F) where F: Fn(&str) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request).to_string();
+    let body = body(request.as_str());
+    requests.lock().unwrap().push(request);
+
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+struct MockConnection {
+    out: Sender,
+    script: HashMap<String, Vec<String>>,
+    close_after_script: bool,
+    ack: bool,
+    received: Arc<Mutex<Vec<String>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Handler for MockConnection {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        let id = self.connections.fetch_add(1, Ordering::SeqCst) + 1;
+        self.out.send(json!({"id": format!("{}", id), "type": "welcome"}).to_string())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let text = format!("{}", msg);
+        self.received.lock().unwrap().push(text.clone());
+
+        let frame: Value = match serde_json::from_str(text.as_str()) {
+            Ok(frame) => frame,
+            Err(_) => return Ok(())
+        };
+
+        match frame["type"].as_str() {
+            Some("ping") => self.out.send(json!({"id": frame["id"], "type": "pong"}).to_string()),
+            Some("subscribe") if !self.ack => Ok(()),
+            Some("subscribe") => {
+                self.out.send(json!({"id": frame["id"], "type": "ack"}).to_string())?;
+
+                let topic = frame["topic"].as_str().unwrap_or("").to_string();
+                for scripted in self.script.get(&topic).cloned().unwrap_or_default() {
+                    self.out.send(scripted)?;
+                }
+
+                if self.close_after_script {
+                    self.out.close(CloseCode::Away)?;
+                }
+
+                Ok(())
+            }
+            _ => Ok(())
+        }
+    }
+}
@@ -0,0 +1,7 @@
+pub mod client;
+pub mod command;
+pub mod decimal;
+pub mod level2;
+pub mod level3;
+#[cfg(test)]
+pub mod mock;
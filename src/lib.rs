@@ -1,15 +1,19 @@
 #![feature(decl_macro)]
 
+extern crate base64;
 extern crate bson;
 extern crate chrono;
 extern crate config;
+extern crate cookie;
 extern crate crypto;
 extern crate hex;
 extern crate httparse;
 #[macro_use]
 extern crate log;
+extern crate mongodb;
 extern crate r2d2_redis;
 extern crate redis;
+extern crate reqwest;
 #[macro_use]
 extern crate rocket;
 #[macro_use]
@@ -21,10 +25,19 @@ extern crate url;
 extern crate walkdir;
 extern crate ws;
 
+mod account;
+mod api_account;
 mod api_user;
+mod auth;
+mod candle;
+mod e1;
+mod exchange;
+mod kucoin;
+mod relay;
 mod user;
 mod ws_server;
 mod event;
 mod utils;
 mod settings;
-mod notifier;
\ No newline at end of file
+mod notifier;
+mod session;
\ No newline at end of file
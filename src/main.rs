@@ -5,8 +5,12 @@
 
 extern crate env_logger;
 #[macro_use]
+extern crate log;
+#[macro_use]
 extern crate rocket;
 
+use std::collections::HashSet;
+use std::env;
 use std::sync::mpsc::channel;
 use std::thread;
 
@@ -17,7 +21,17 @@ use rocket_contrib::templates::Template;
 
 use crate::ws_server;
 use crate::user;
+use crate::account;
+use crate::api_account;
 use crate::api_user;
+use crate::candle;
+use crate::relay;
+use crate::settings;
+use crate::settings::ws::get_connect_string;
+use crate::event::Event;
+use crate::user::store::{MongoUserStore, Users};
+use crate::ws_server::limits::Limits;
+use crate::ws_server::multicast::multicast;
 
 
 #[get("/")]
@@ -48,26 +62,82 @@ fn wsserver() {
 }
 
 fn main() {
+    let settings = settings::Settings::new(
+        env::var("RUN_MODE").unwrap_or("development".to_string()).as_str(),
+        env::var("CONFIG_PATH").unwrap_or("config".to_string()).as_str(),
+    );
+
+    // Rocket has to encrypt private cookies with the key the websocket handshake decrypts them with
+    if let Some(secret_key) = settings.get_auth().get_secret_key() {
+        env::set_var("ROCKET_SECRET_KEY", secret_key);
+    }
+
+    let users: Users = match MongoUserStore::new(&settings.get_db_mongo()) {
+        Ok(store) => Box::new(store),
+        Err(e) => panic!("{}", e)
+    };
+
     thread::spawn(move || wsserver());
 
 
     let (tx, rx) = channel::<Event>();
     let (tx_logging, rx_logging) = channel::<Event>();
 
+    // Channels fed by the relay and candle threads, clients cannot post into them
+    let mut owned = HashSet::new();
+
+    if let Some(relay) = settings.get_relay() {
+        owned.extend(relay::spawn(&relay, tx.clone()));
+    }
+
+    if let Some(candles) = settings.get_candle() {
+        owned.extend(candle::spawn(&candles, tx.clone()));
+    }
+
+    let account = settings.get_account().map(|account| {
+        let tracker = account::spawn(&account);
+        (account, tracker)
+    });
 
-    thread::spawn(|| ws::listen("127.0.0.1:3001", |out, rx| ws_server::server::Server::new(out, rx)));
+    thread::spawn(move || multicast(rx, tx_logging, owned));
+
+    thread::spawn(move || {
+        for event in rx_logging {
+            if let Event::Logging(message) = event {
+                info!("[{}] {}: {}", message.channel, message.ip, message.message);
+            }
+        }
+    });
 
-    rocket::ignite()
+    let ws = settings.get_ws().clone();
+    let auth = settings.get_auth().clone();
+    thread::spawn(move || {
+        let limits = Limits::from_settings(&ws);
+        ws_server::run_server(get_connect_string(&ws).as_str(), ws.get_max_connections(), tx, auth, limits)
+    });
+
+    let mut rocket = rocket::ignite()
         .mount("/", routes![index])
         .mount("/hello", routes![hello])
         .mount(
             "/api/v1/user",
-            routes![api_user::user_id, api_user::logout, api_user::hello],
+            routes![api_user::user_id, api_user::login, api_user::register, api_user::ws_token, api_user::logout, api_user::hello],
         )
         .mount("/api/cookie", routes![
             user::cookie::index,
             user::cookie::submit,
         ])
-        .attach(Template::fairing())
-        .launch();
+        .manage(settings.get_auth().clone())
+        .manage(settings.get_ws().clone())
+        .manage(users)
+        .attach(Template::fairing());
+
+    if let Some((account, tracker)) = account {
+        rocket = rocket
+            .mount("/api/v1/account", routes![api_account::snapshot])
+            .manage(account)
+            .manage(tracker);
+    }
+
+    rocket.launch();
 }
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender as ThreadSender;
+use std::thread;
+
+use crate::event::{Event, RelayMessage};
+use crate::exchange::{ExchangeAdapter, MarketEvent, MarketUpdate};
+use crate::exchange::kucoin::KucoinAdapter;
+use crate::settings::relay::{Format, RelaySettings, Route};
+use crate::utils::normalize_group;
+
+/// Maps upstream topics to the multicast channels they are republished into.
+pub struct Routes {
+    routes: HashMap<String, Route>,
+}
+
+impl Routes {
+    /// Fails on normalized routes for topics `adapter` has no normalized events for.
+    pub fn new(routes: Vec<Route>, adapter: &dyn ExchangeAdapter) -> Result<Self, String> {
+        for route in routes.iter() {
+            if route.get_format() == Format::Normalized && !adapter.normalizes(route.get_topic().as_str()) {
+                return Err(format!("Topic [{}] has no normalized events, relay it raw", route.get_topic()));
+            }
+        }
+
+        Ok(Routes {
+            routes: routes.into_iter().map(|route| (route.get_topic(), route)).collect(),
+        })
+    }
+
+    /// Channels routed topics are republished into.
+    pub fn get_channels(&self) -> Vec<String> {
+        self.routes.values().map(|route| normalize_group(route.get_channel().as_str())).collect()
+    }
+
+    pub fn get_topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self.routes.keys().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    /// Relay message for `update`, or `None` for unrouted topics and for raw events on
+    /// normalized routes.
+    pub fn resolve(&self, update: &MarketUpdate) -> Option<RelayMessage> {
+        let route = self.routes.get(&update.topic)?;
+        let message = match (route.get_format(), &update.event) {
+            (Format::Raw, _) => Ok(update.raw.clone()),
+            (Format::Normalized, MarketEvent::Raw { .. }) => return None,
+            (Format::Normalized, _) => serde_json::to_string(update),
+        };
+
+        match message {
+            Ok(message) => Some(RelayMessage {
+                channel: normalize_group(route.get_channel().as_str()),
+                message: message,
+                snapshot: is_snapshot(&update.event) && route.get_snapshot(),
+            }),
+            Err(e) => {
+                error!("Cannot serialize {} update: {}", update.topic, e);
+                None
+            }
+        }
+    }
+}
+
+fn is_snapshot(event: &MarketEvent) -> bool {
+    match event {
+        MarketEvent::Ticker(_) => true,
+        MarketEvent::Raw { snapshot } => *snapshot,
+        _ => false,
+    }
+}
+
+/// Consumes the upstream feed once and republishes routed topics until the multicast
+/// thread is gone.
+pub fn relay(rx: Receiver<MarketUpdate>, tx: ThreadSender<Event>, routes: Routes) {
+    for update in rx {
+        if let Some(message) = routes.resolve(&update) {
+            if tx.send(Event::Relay(message)).is_err() {
+                error!("Multicast is gone, stop relay");
+                return;
+            }
+        }
+    }
+}
+
+/// Starts the relay and returns the channels it owns.
+pub fn spawn(settings: &RelaySettings, tx: ThreadSender<Event>) -> Vec<String> {
+    let adapter = KucoinAdapter::new(settings.get_rest_url().as_str());
+    let routes = match Routes::new(settings.get_routes(), &adapter) {
+        Ok(routes) => routes,
+        Err(e) => panic!("Invalid relay routes: {}", e),
+    };
+    let channels = routes.get_channels();
+    let rx = adapter.connect(routes.get_topics());
+
+    thread::spawn(move || relay(rx, tx, routes));
+    channels
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::e1::parse_text;
+    use crate::event::Event;
+    use crate::exchange::{ExchangeAdapter, MarketUpdate};
+    use crate::exchange::kucoin::{KucoinAdapter, normalize};
+    use crate::kucoin::mock::MockExchange;
+    use crate::relay::{relay, Routes};
+    use crate::settings::relay::{Format, Route};
+
+    fn update(fixture: &str) -> MarketUpdate {
+        normalize(fixture, &parse_text(fixture).unwrap()).unwrap()
+    }
+
+    fn routes() -> Routes {
+        Routes::new(vec![
+            Route::new("/market/ticker:BTC-USDT", "/Ticker/BTC-USDT", None),
+            Route::new("/market/match:BTC-USDT", "match/btc-usdt", None).with_format(Format::Normalized),
+            Route::new("/indicator/index:USDT-BTC", "index/usdt-btc", None),
+            Route::new("/market/snapshot:KCS-BTC", "snapshot/kcs-btc", None),
+        ], &KucoinAdapter::new("http://localhost")).unwrap()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let routes = routes();
+
+        let ticker = routes.resolve(&update(include_str!("../../fixtures/kucoin/ticker.json"))).unwrap();
+        assert_eq!("ticker/btc-usdt", ticker.channel.as_str());
+        assert!(ticker.snapshot);
+        assert!(ticker.message.contains("\"bestAsk\":\"0.08\""));
+
+        let trade = routes.resolve(&update(include_str!("../../fixtures/kucoin/match.json"))).unwrap();
+        assert_eq!("match/btc-usdt", trade.channel.as_str());
+        assert!(!trade.snapshot);
+        assert!(trade.message.contains("\"type\":\"trade\""));
+        assert!(trade.message.contains("\"trade_id\""));
+
+        let unknown = include_str!("../../fixtures/kucoin/match.json").replace("\"side\":\"buy\"", "\"side\":\"short\"");
+        assert!(routes.resolve(&update(unknown.as_str())).is_none());
+
+        let index = routes.resolve(&update(include_str!("../../fixtures/kucoin/index_price.json"))).unwrap();
+        assert_eq!("index/usdt-btc", index.channel.as_str());
+        assert!(!index.snapshot);
+        assert_eq!(include_str!("../../fixtures/kucoin/index_price.json"), index.message.as_str());
+
+        let snapshot = routes.resolve(&update(include_str!("../../fixtures/kucoin/snapshot.json"))).unwrap();
+        assert!(snapshot.snapshot);
+        assert!(snapshot.message.contains("\"lastTradedPrice\""));
+
+        assert!(routes.resolve(&update(include_str!("../../fixtures/kucoin/all_ticker.json"))).is_none());
+
+        let mut channels = routes.get_channels();
+        channels.sort();
+        assert_eq!(vec!["index/usdt-btc", "match/btc-usdt", "snapshot/kcs-btc", "ticker/btc-usdt"], channels);
+    }
+
+    #[test]
+    fn test_reject_normalized_without_events() {
+        let adapter = KucoinAdapter::new("http://localhost");
+
+        for topic in &["/indicator/index:USDT-BTC", "/indicator/markPrice:USDT-BTC", "/margin/fundingBook:USDT", "/market/level3:BTC-USDT", "/market/snapshot:KCS-BTC"] {
+            assert!(Routes::new(vec![Route::new(topic, "channel", None).with_format(Format::Normalized)], &adapter).is_err());
+            assert!(Routes::new(vec![Route::new(topic, "channel", None)], &adapter).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_relay_from_upstream() {
+        let exchange = MockExchange::start(vec![("/market/ticker:BTC-USDT", vec![include_str!("../../fixtures/kucoin/ticker.json")])], false);
+        let routes = routes();
+        let rx = KucoinAdapter::new(exchange.rest_url.as_str()).connect(routes.get_topics());
+        let (tx, events) = channel();
+
+        thread::spawn(move || relay(rx, tx, routes));
+
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(Event::Relay(message)) => {
+                assert_eq!("ticker/btc-usdt", message.channel.as_str());
+                assert!(message.snapshot);
+            }
+            _ => panic!("ticker was not relayed"),
+        }
+        assert!(exchange.received().iter().any(|frame| frame.contains("/market/match:BTC-USDT")));
+    }
+}
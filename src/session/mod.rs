@@ -0,0 +1,148 @@
+use cookie::{Cookie, CookieJar, Key};
+
+use crate::settings::auth::Authorization;
+
+/// Name of the private cookie holding the logged in user's id.
+pub const USER_ID_COOKIE: &str = "user_id";
+
+/// Name of the private cookie holding the comma separated channels the user may join.
+pub const CHANNELS_COOKIE: &str = "channels";
+
+/// Reads private cookies issued by Rocket outside of a Rocket request.
+///
+/// Rocket encrypts private cookies with a key derived from its `secret_key`. Building the
+/// same key from `Authorization.secret_key` lets the websocket handshake decrypt them too.
+#[derive(Clone)]
+pub struct SessionKey {
+    key: Key,
+}
+
+impl SessionKey {
+    pub fn new(secret_key: &str) -> Option<Self> {
+        let master = match base64::decode(secret_key) {
+            Ok(master) => master,
+            Err(e) => {
+                error!("Session secret key is not valid base64: {}", e);
+                return None;
+            }
+        };
+
+        if master.len() < 32 {
+            error!("Session secret key must be at least 256 bits, got {} bits", master.len() * 8);
+            return None;
+        }
+
+        Some(SessionKey { key: Key::from_master(&master) })
+    }
+
+    /// Browsers send cookies along with cross-site websocket handshakes, so cookie sessions
+    /// are only enabled together with a non-empty `allowed_origins`.
+    pub fn from_auth(auth: &Authorization) -> Option<Self> {
+        let secret_key = auth.get_secret_key()?;
+
+        if auth.get_allowed_origins().map_or(true, |origins| origins.is_empty()) {
+            error!("Cookie sessions need allowed_origins, websocket handshakes will ignore cookies");
+            return None;
+        }
+
+        SessionKey::new(secret_key.as_str())
+    }
+
+    /// Returns the verified value of the private cookie `name` from a raw `Cookie` header.
+    pub fn get_private(&self, header: &str, name: &str) -> Option<String> {
+        let mut jar = CookieJar::new();
+
+        for pair in header.split(';') {
+            if let Ok(cookie) = Cookie::parse(pair.trim()) {
+                jar.add_original(cookie.into_owned());
+            }
+        }
+
+        jar.private(&self.key).get(name).map(|cookie| cookie.value().to_string())
+    }
+
+    pub fn get_user_id(&self, header: &str) -> Option<String> {
+        self.get_private(header, USER_ID_COOKIE)
+    }
+
+    pub fn get_channels(&self, header: &str) -> Vec<String> {
+        match self.get_private(header, CHANNELS_COOKIE) {
+            Some(channels) => channels.split(',').filter(|channel| !channel.is_empty()).map(String::from).collect(),
+            None => vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cookie::{Cookie, CookieJar};
+
+    use crate::session::{SessionKey, CHANNELS_COOKIE, USER_ID_COOKIE};
+    use crate::settings::auth::Authorization;
+
+    const SECRET_KEY: &str = "8Xui8SN4mI+7egV/9dlfYYLGQJeEx4+DwmSQLwDVXJg=";
+
+    fn encrypt(key: &SessionKey, name: &str, value: &str) -> String {
+        let mut jar = CookieJar::new();
+        jar.private(&key.key).add(Cookie::new(name.to_string(), value.to_string()));
+        let cookie = jar.get(name).unwrap();
+        format!("{}={}", cookie.name(), cookie.value())
+    }
+
+    #[test]
+    fn test_new() {
+        assert!(SessionKey::new(SECRET_KEY).is_some());
+        assert!(SessionKey::new("c2hvcnQ=").is_none());
+        assert!(SessionKey::new("not base64!").is_none());
+    }
+
+    #[test]
+    fn test_get_user_id() {
+        let key = SessionKey::new(SECRET_KEY).unwrap();
+        let header = format!("message=hello; {}", encrypt(&key, USER_ID_COOKIE, "42"));
+
+        assert_eq!(Some("42".to_string()), key.get_user_id(header.as_str()));
+        assert_eq!(None, key.get_user_id("user_id=42"));
+        assert_eq!(None, key.get_user_id(""));
+    }
+
+    #[test]
+    fn test_from_auth_needs_allowed_origins() {
+        let mut auth = Authorization {
+            private_key: "usocksecret".to_string(),
+            keep_alive: None,
+            token_name: None,
+            time_name: None,
+            secret_key: Some(SECRET_KEY.to_string()),
+            channels_name: None,
+            user_name: None,
+            allowed_origins: None,
+            default_channels: None,
+        };
+        assert!(SessionKey::from_auth(&auth).is_none());
+
+        auth.allowed_origins = Some(vec![]);
+        assert!(SessionKey::from_auth(&auth).is_none());
+
+        auth.allowed_origins = Some(vec!["https://example.com".to_string()]);
+        assert!(SessionKey::from_auth(&auth).is_some());
+    }
+
+    #[test]
+    fn test_get_channels() {
+        let key = SessionKey::new(SECRET_KEY).unwrap();
+        let header = encrypt(&key, CHANNELS_COOKIE, "news,hello/world");
+
+        assert_eq!(vec!["news".to_string(), "hello/world".to_string()], key.get_channels(header.as_str()));
+        assert!(key.get_channels("channels=news").is_empty());
+    }
+
+    #[test]
+    fn test_get_user_id_other_key() {
+        let key = SessionKey::new(SECRET_KEY).unwrap();
+        let other = SessionKey::new("p9mJ3q1xk5uZ9dDqZbqN6lq8bJ0oYtPp0QeN9y3nV2o=").unwrap();
+        let header = encrypt(&other, USER_ID_COOKIE, "42");
+
+        assert_eq!(None, key.get_user_id(header.as_str()));
+    }
+}
@@ -0,0 +1,55 @@
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccountSettings {
+    rest_url: String,
+    api_key: String,
+    api_secret: String,
+    api_passphrase: String,
+    topics: Option<Vec<String>>,
+    loan_currencies: Option<Vec<String>>,
+    readers: Option<Vec<String>>,
+}
+
+impl AccountSettings {
+    pub fn get_rest_url(&self) -> String {
+        self.rest_url.clone()
+    }
+
+    pub fn get_api_key(&self) -> String {
+        self.api_key.clone()
+    }
+
+    pub fn get_api_secret(&self) -> String {
+        self.api_secret.clone()
+    }
+
+    pub fn get_api_passphrase(&self) -> String {
+        self.api_passphrase.clone()
+    }
+
+    /// Ids of the users allowed to read the account, none by default.
+    pub fn get_readers(&self) -> Vec<String> {
+        self.readers.clone().unwrap_or_default()
+    }
+
+    /// Currencies whose margin loans are tracked, USDT by default.
+    pub fn get_loan_currencies(&self) -> Vec<String> {
+        self.loan_currencies.clone().unwrap_or_else(|| vec!["USDT".to_string()])
+    }
+
+    /// Private topics to track, balances, stop orders, margin position and the margin loans
+    /// of each loan currency by default.
+    pub fn get_topics(&self) -> Vec<String> {
+        match &self.topics {
+            Some(topics) => topics.clone(),
+            None => {
+                let mut topics = vec![
+                    "/account/balance".to_string(),
+                    "/spotMarket/advancedOrders".to_string(),
+                    "/margin/position".to_string(),
+                ];
+                topics.extend(self.get_loan_currencies().iter().map(|currency| format!("/margin/loan:{}", currency)));
+                topics
+            }
+        }
+    }
+}
@@ -4,6 +4,11 @@ pub struct Authorization {
     pub keep_alive: Option<i64>,
     pub token_name: Option<String>,
     pub time_name: Option<String>,
+    pub secret_key: Option<String>,
+    pub channels_name: Option<String>,
+    pub user_name: Option<String>,
+    pub allowed_origins: Option<Vec<String>>,
+    pub default_channels: Option<Vec<String>>,
 }
 
 impl Authorization {
@@ -22,4 +27,25 @@ impl Authorization {
     pub fn get_time_name(&self) -> Option<String> {
         self.time_name.clone()
     }
+
+    pub fn get_secret_key(&self) -> Option<String> {
+        self.secret_key.clone()
+    }
+
+    pub fn get_channels_name(&self) -> Option<String> {
+        self.channels_name.clone()
+    }
+
+    pub fn get_user_name(&self) -> Option<String> {
+        self.user_name.clone()
+    }
+
+    pub fn get_allowed_origins(&self) -> Option<Vec<String>> {
+        self.allowed_origins.clone()
+    }
+
+    /// Channels new users may join.
+    pub fn get_default_channels(&self) -> Vec<String> {
+        self.default_channels.clone().unwrap_or_default()
+    }
 }
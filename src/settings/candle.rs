@@ -0,0 +1,26 @@
+#[derive(Debug, Deserialize, Clone)]
+pub struct CandleSettings {
+    rest_url: String,
+    symbols: Vec<String>,
+    intervals: Vec<String>,
+    grace_ms: Option<i64>,
+}
+
+impl CandleSettings {
+    pub fn get_rest_url(&self) -> String {
+        self.rest_url.clone()
+    }
+
+    pub fn get_symbols(&self) -> Vec<String> {
+        self.symbols.clone()
+    }
+
+    pub fn get_intervals(&self) -> Vec<String> {
+        self.intervals.clone()
+    }
+
+    /// How long a bar stays open for late trades after its interval ended.
+    pub fn get_grace_ms(&self) -> i64 {
+        self.grace_ms.unwrap_or(2000)
+    }
+}
@@ -2,9 +2,12 @@ use config::{Config, File};
 use walkdir::{DirEntry, WalkDir};
 
 pub mod ws;
+pub mod account;
 pub mod auth;
+pub mod candle;
 pub mod db;
 pub mod rd;
+pub mod relay;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
@@ -12,6 +15,9 @@ pub struct Settings {
     auth: auth::Authorization,
     mongo: db::MongoSettings,
     rd: rd::RdConfig,
+    relay: Option<relay::RelaySettings>,
+    candle: Option<candle::CandleSettings>,
+    account: Option<account::AccountSettings>,
 }
 
 impl Settings {
@@ -50,6 +56,18 @@ impl Settings {
     pub fn get_rd(&self) -> rd::RdConfig {
         self.rd.clone()
     }
+
+    pub fn get_relay(&self) -> Option<relay::RelaySettings> {
+        self.relay.clone()
+    }
+
+    pub fn get_candle(&self) -> Option<candle::CandleSettings> {
+        self.candle.clone()
+    }
+
+    pub fn get_account(&self) -> Option<account::AccountSettings> {
+        self.account.clone()
+    }
 }
 
 fn is_hidden(entry: &DirEntry) -> bool {
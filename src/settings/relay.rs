@@ -0,0 +1,67 @@
+#[derive(Debug, Deserialize, Clone)]
+pub struct RelaySettings {
+    rest_url: String,
+    routes: Vec<Route>,
+}
+
+/// Upstream topic republished into a multicast channel.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Route {
+    topic: String,
+    channel: String,
+    snapshot: Option<bool>,
+    format: Option<Format>,
+}
+
+/// Wire format of a route. `raw` relays the exchange's own messages, `normalized` relays
+/// the exchange independent events and only exists for topics that have them.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Raw,
+    Normalized,
+}
+
+impl RelaySettings {
+    pub fn get_rest_url(&self) -> String {
+        self.rest_url.clone()
+    }
+
+    pub fn get_routes(&self) -> Vec<Route> {
+        self.routes.clone()
+    }
+}
+
+impl Route {
+    pub fn new(topic: &str, channel: &str, snapshot: Option<bool>) -> Self {
+        Route {
+            topic: topic.to_string(),
+            channel: channel.to_string(),
+            snapshot: snapshot,
+            format: None,
+        }
+    }
+
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn get_topic(&self) -> String {
+        self.topic.clone()
+    }
+
+    pub fn get_channel(&self) -> String {
+        self.channel.clone()
+    }
+
+    /// Tickers keep their last message for clients that join later unless disabled.
+    pub fn get_snapshot(&self) -> bool {
+        self.snapshot.unwrap_or(true)
+    }
+
+    /// Routes keep relaying the exchange's own messages unless they opt into normalized events.
+    pub fn get_format(&self) -> Format {
+        self.format.unwrap_or(Format::Raw)
+    }
+}
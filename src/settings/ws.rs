@@ -1,9 +1,12 @@
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct WsServer {
     ssl: Option<Ssl>,
     host: String,
     port: u16,
     max_connections: usize,
+    max_connections_per_user: Option<usize>,
+    max_connections_per_channel: Option<usize>,
+    evict_oldest: Option<bool>,
 }
 
 impl WsServer {
@@ -22,6 +25,18 @@ impl WsServer {
     pub fn get_max_connections(&self) -> usize {
         self.max_connections.clone()
     }
+
+    pub fn get_max_connections_per_user(&self) -> Option<usize> {
+        self.max_connections_per_user.clone()
+    }
+
+    pub fn get_max_connections_per_channel(&self) -> Option<usize> {
+        self.max_connections_per_channel.clone()
+    }
+
+    pub fn get_evict_oldest(&self) -> bool {
+        self.evict_oldest.unwrap_or(false)
+    }
 }
 
 pub fn get_connect_string(settings: &WsServer) -> String {
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use bson::{doc, Bson};
+use crypto::pbkdf2::{pbkdf2_check, pbkdf2_simple};
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+use mongodb::{Client, Collection};
+
+use crate::settings::db::MongoSettings;
+
+/// Number of PBKDF2 rounds used for new password hashes.
+const HASH_ROUNDS: u32 = 10_000;
+
+/// MongoDB error code for a write that violates a unique index.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// A registered user and the channels it may join.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+    pub password_hash: String,
+    pub channels: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StoreError {
+    AlreadyExists(String),
+    InvalidCredentials,
+    Backend(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::AlreadyExists(name) => write!(f, "User [{}] already exists", name),
+            StoreError::InvalidCredentials => write!(f, "Invalid user name or password"),
+            StoreError::Backend(e) => write!(f, "User store failed: {}", e),
+        }
+    }
+}
+
+/// Storage for registered users. Passwords only ever reach a store as salted hashes.
+pub trait UserStore: Send + Sync {
+    fn find_by_name(&self, name: &str) -> Result<Option<User>, StoreError>;
+
+    /// Fails with `AlreadyExists` when the name is taken, even by a concurrent insert.
+    fn insert(&self, name: &str, password_hash: &str, channels: &[String]) -> Result<User, StoreError>;
+
+    fn register(&self, name: &str, password: &str, channels: &[String]) -> Result<User, StoreError> {
+        if self.find_by_name(name)?.is_some() {
+            return Err(StoreError::AlreadyExists(name.to_string()));
+        }
+
+        let password_hash = match pbkdf2_simple(password, HASH_ROUNDS) {
+            Ok(hash) => hash,
+            Err(e) => return Err(StoreError::Backend(format!("Cannot hash password: {}", e)))
+        };
+
+        self.insert(name, password_hash.as_str(), channels)
+    }
+
+    fn authenticate(&self, name: &str, password: &str) -> Result<User, StoreError> {
+        let user = match self.find_by_name(name)? {
+            Some(user) => user,
+            None => {
+                // Hash anyway, so an unknown name takes as long as a wrong password
+                let _ = pbkdf2_simple(password, HASH_ROUNDS);
+                return Err(StoreError::InvalidCredentials);
+            }
+        };
+
+        match pbkdf2_check(password, user.password_hash.as_str()) {
+            Ok(true) => Ok(user),
+            Ok(false) => Err(StoreError::InvalidCredentials),
+            Err(e) => Err(StoreError::Backend(format!("Stored password hash of [{}] is broken: {}", name, e)))
+        }
+    }
+}
+
+pub type Users = Box<dyn UserStore>;
+
+#[derive(Default)]
+pub struct MemoryUserStore {
+    users: Mutex<HashMap<String, User>>,
+}
+
+impl MemoryUserStore {
+    pub fn new() -> Self {
+        MemoryUserStore::default()
+    }
+}
+
+impl UserStore for MemoryUserStore {
+    fn find_by_name(&self, name: &str) -> Result<Option<User>, StoreError> {
+        let users = self.users.lock().unwrap();
+        Ok(users.get(name).cloned())
+    }
+
+    fn insert(&self, name: &str, password_hash: &str, channels: &[String]) -> Result<User, StoreError> {
+        let mut users = self.users.lock().unwrap();
+
+        if users.contains_key(name) {
+            return Err(StoreError::AlreadyExists(name.to_string()));
+        }
+
+        let user = User {
+            id: format!("{}", users.len() + 1),
+            name: name.to_string(),
+            password_hash: password_hash.to_string(),
+            channels: channels.to_vec(),
+        };
+        users.insert(name.to_string(), user.clone());
+
+        Ok(user)
+    }
+}
+
+pub struct MongoUserStore {
+    collection: Collection,
+}
+
+impl MongoUserStore {
+    /// Connects and makes sure names are unique, so concurrent registrations of the same
+    /// name cannot both succeed.
+    pub fn new(settings: &MongoSettings) -> Result<Self, StoreError> {
+        let client = Client::with_uri_str(settings.get_uri().as_str())
+            .map_err(|e| StoreError::Backend(format!("Cannot connect to {}: {}", settings.get_uri(), e)))?;
+        let database = client.database(settings.get_db_name().as_str());
+
+        database
+            .run_command(doc! {
+                "createIndexes": settings.get_table_name(),
+                "indexes": [{"key": {"name": 1}, "name": "name_unique", "unique": true}],
+            }, None)
+            .map_err(|e| StoreError::Backend(format!("Cannot create unique index on name: {}", e)))?;
+
+        Ok(MongoUserStore {
+            collection: database.collection(settings.get_table_name().as_str()),
+        })
+    }
+}
+
+fn is_duplicate_key(e: &Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::WriteError(WriteFailure::WriteError(write_error)) => write_error.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+impl UserStore for MongoUserStore {
+    fn find_by_name(&self, name: &str) -> Result<Option<User>, StoreError> {
+        let document = self.collection
+            .find_one(Some(doc! {"name": name}), None)
+            .map_err(|e| StoreError::Backend(format!("{}", e)))?;
+
+        let document = match document {
+            Some(document) => document,
+            None => return Ok(None)
+        };
+
+        let id = match document.get("_id") {
+            Some(Bson::ObjectId(id)) => id.to_hex(),
+            Some(id) => format!("{}", id),
+            None => return Err(StoreError::Backend(format!("User [{}] has no _id", name)))
+        };
+
+        // Users stored before channels were kept may join none
+        let channels = document.get_array("channels")
+            .map(|channels| channels.iter().filter_map(|channel| channel.as_str().map(String::from)).collect())
+            .unwrap_or_default();
+
+        match document.get_str("password_hash") {
+            Ok(password_hash) => Ok(Some(User {
+                id: id,
+                name: name.to_string(),
+                password_hash: password_hash.to_string(),
+                channels: channels,
+            })),
+            Err(e) => Err(StoreError::Backend(format!("User [{}] has no password hash: {}", name, e)))
+        }
+    }
+
+    fn insert(&self, name: &str, password_hash: &str, channels: &[String]) -> Result<User, StoreError> {
+        let result = self.collection
+            .insert_one(doc! {"name": name, "password_hash": password_hash, "channels": channels.to_vec()}, None)
+            .map_err(|e| match is_duplicate_key(&e) {
+                true => StoreError::AlreadyExists(name.to_string()),
+                false => StoreError::Backend(format!("{}", e)),
+            })?;
+
+        let id = match result.inserted_id {
+            Bson::ObjectId(id) => id.to_hex(),
+            id => format!("{}", id),
+        };
+
+        Ok(User {
+            id: id,
+            name: name.to_string(),
+            password_hash: password_hash.to_string(),
+            channels: channels.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::user::store::{MemoryUserStore, StoreError, UserStore};
+
+    #[test]
+    fn test_register() {
+        let store = MemoryUserStore::new();
+        let user = store.register("alice", "secret", &["news".to_string()]).unwrap();
+
+        assert_eq!("alice", user.name.as_str());
+        assert_ne!("secret", user.password_hash.as_str());
+        assert_eq!(vec!["news".to_string()], user.channels);
+        assert_eq!(Err(StoreError::AlreadyExists("alice".to_string())), store.register("alice", "other", &[]));
+    }
+
+    #[test]
+    fn test_insert_duplicate() {
+        let store = MemoryUserStore::new();
+        store.insert("alice", "hash", &[]).unwrap();
+
+        assert_eq!(Err(StoreError::AlreadyExists("alice".to_string())), store.insert("alice", "other", &[]));
+    }
+
+    #[test]
+    fn test_password_hash_is_salted() {
+        let store = MemoryUserStore::new();
+        let alice = store.register("alice", "secret", &[]).unwrap();
+        let bob = store.register("bob", "secret", &[]).unwrap();
+
+        assert_ne!(alice.password_hash, bob.password_hash);
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let store = MemoryUserStore::new();
+        let user = store.register("alice", "secret", &[]).unwrap();
+
+        assert_eq!(Ok(user), store.authenticate("alice", "secret"));
+        assert_eq!(Err(StoreError::InvalidCredentials), store.authenticate("alice", "wrong"));
+        assert_eq!(Err(StoreError::InvalidCredentials), store.authenticate("bob", "secret"));
+    }
+}
@@ -2,11 +2,13 @@ use chrono::Utc;
 use crypto::hmac::Hmac;
 use crypto::mac::Mac;
 use crypto::sha1;
+use crypto::util::fixed_time_eq;
 use httparse;
 use url::Url;
 use ws::{Error, ErrorKind, Result};
 
 
+use crate::auth::AuthError;
 use crate::settings::auth::Authorization;
 
 pub struct HttpData {
@@ -23,44 +25,65 @@ impl HttpData {
     }
 
     pub fn get_group(&self) -> String {
-        let mut group = self.url.path().to_lowercase();
+        normalize_group(self.url.path())
+    }
 
-        if group.starts_with("/") {
-            group.remove(0);
-        }
+    /// Channels a scoped token was issued for, if the request carries any.
+    pub fn get_channels(&self) -> Option<String> {
+        let channels_name = self.auth.get_channels_name().unwrap_or("channels".to_string());
 
-        let len = match group.len() > 0 {
-            true => group.len() - 1,
-            _ => 0
-        };
+        self.url.query_pairs()
+            .find(|(key, _)| key == channels_name.as_str())
+            .map(|(_, value)| value.to_string())
+    }
 
-        if group.ends_with("/") {
-            group.remove(len);
-        }
+    /// User a token was issued to, if the request carries one.
+    pub fn get_user(&self) -> Option<String> {
+        let user_name = self.auth.get_user_name().unwrap_or("user".to_string());
 
-        group
+        self.url.query_pairs()
+            .find(|(key, _)| key == user_name.as_str())
+            .map(|(_, value)| value.to_string())
     }
 
-    pub fn validate(&self) -> Option<Error> {
+    pub fn validate(&self) -> Option<AuthError> {
         let (token, public_key) = match self.get_token_and_public_key(
             self.auth.get_token_name().unwrap_or("token".to_string()).as_str(),
             self.auth.get_time_name().unwrap_or("nonce".to_string()).as_str(),
         ) {
             Some((t, k)) => (t, k),
-            _ => return Some(Error::new(ErrorKind::Http(httparse::Error::Token), format!("Not valid request.")))
+            _ => return Some(AuthError::MissingToken)
         };
 
         let public_key_time: i64 = match public_key.parse() {
             Ok(k) => k,
-            Err(_) => return Some(Error::new(ErrorKind::Http(httparse::Error::Token), format!("Not valid request. Public key is not integer: {:?}", public_key)))
+            Err(_) => return Some(AuthError::BadNonce(public_key))
         };
 
         if self.validate_time(public_key_time, self.auth.get_keep_alive()) == false {
-            return Some(Error::new(ErrorKind::Http(httparse::Error::Token), format!("Not valid request. Expired: {:?}", public_key)));
+            return Some(AuthError::Expired(public_key_time));
         }
 
+        let public_key = match self.get_channels() {
+            Some(channels) => {
+                let group = self.get_group();
+
+                if channels.split(',').all(|channel| normalize_group(channel) != group) {
+                    return Some(AuthError::ForbiddenChannel(group));
+                }
+
+                scope_public_key(public_key.as_str(), channels.as_str())
+            }
+            None => public_key
+        };
+
+        let public_key = match self.get_user() {
+            Some(user) => scope_user(public_key.as_str(), user.as_str()),
+            None => public_key
+        };
+
         if self.validate_token(token.as_str(), public_key.as_str()) == false {
-            return Some(Error::new(ErrorKind::Http(httparse::Error::Token), format!("Not valid request. Token not valid: {}", token)));
+            return Some(AuthError::BadSignature);
         }
 
         None
@@ -98,16 +121,16 @@ impl HttpData {
         }
     }
 
+    /// Checks the HMAC-SHA1 signature of the public key. Handshakes were accepted with any
+    /// token before, so clients must now sign with the configured `private_key`.
     fn validate_token(&self, token: &str, public_key: &str) -> bool {
-        let mut auth = Hmac::new(sha1::Sha1::new(), self.auth.get_private_key().as_bytes());
-        auth.input(public_key.as_bytes());
-        format!("{}",token.to_string());
+        let expected = sign_token(self.auth.get_private_key().as_str(), public_key);
 
-        //if token != auth.result().code().to_string() {
-        //    error!("Token not valid. Got [{}], should [{}]", token, auth.result().code().to_hex());
-        //}
+        if !fixed_time_eq(token.as_bytes(), expected.as_bytes()) {
+            error!("Token not valid. Got [{}] for public key [{}]", token, public_key);
+            return false;
+        }
 
-        //token == auth.result().code().to_hex()
         true
     }
 
@@ -122,13 +145,52 @@ impl HttpData {
     }
 }
 
+/// Lowercases a channel name and strips one leading and trailing slash, like request paths.
+pub fn normalize_group(channel: &str) -> String {
+    let mut group = channel.to_lowercase();
+
+    if group.starts_with("/") {
+        group.remove(0);
+    }
+
+    let len = match group.len() > 0 {
+        true => group.len() - 1,
+        _ => 0
+    };
+
+    if group.ends_with("/") {
+        group.remove(len);
+    }
+
+    group
+}
+
+/// Binds a public key to a comma separated channel list, so a token signed over the result
+/// is only valid for those channels.
+pub fn scope_public_key(public_key: &str, channels: &str) -> String {
+    format!("{}:{}", public_key, channels)
+}
+
+/// Binds a public key to the user the token is issued to.
+pub fn scope_user(public_key: &str, user: &str) -> String {
+    format!("{}@{}", public_key, user)
+}
+
+/// Signs a public key (nonce) with the private key the same way clients are expected to.
+pub fn sign_token(private_key: &str, public_key: &str) -> String {
+    let mut auth = Hmac::new(sha1::Sha1::new(), private_key.as_bytes());
+    auth.input(public_key.as_bytes());
+    hex::encode(auth.result().code())
+}
+
 #[cfg(test)]
 mod test {
     use chrono::{DateTime, Utc};
     use crypto::{hmac, sha1};
     use crypto::mac::Mac;
 
-    use crate::utils::HttpData;
+    use crate::auth::AuthError;
+    use crate::utils::{HttpData, normalize_group, scope_public_key, scope_user, sign_token};
     use crate::settings::auth::Authorization;
 
     fn get_auth_default() -> Authorization {
@@ -137,6 +199,11 @@ mod test {
             keep_alive: None,
             token_name: None,
             time_name: None,
+            secret_key: None,
+            channels_name: None,
+            user_name: None,
+            allowed_origins: None,
+            default_channels: None,
         }
     }
 
@@ -151,6 +218,21 @@ mod test {
         assert_eq!(data.get_group(), "hello/world".to_string());
     }
 
+    #[test]
+    fn test_normalize_group() {
+        assert_eq!("hello/world", normalize_group("/Hello/World/"));
+        assert_eq!("hello", normalize_group("hello"));
+        assert_eq!("", normalize_group("/"));
+    }
+
+    #[test]
+    fn test_get_channels() {
+        let data: HttpData = HttpData::new("/hello?channels=hello,world", get_auth_default()).unwrap();
+        assert_eq!(Some("hello,world".to_string()), data.get_channels());
+        let data: HttpData = HttpData::new("/hello?nonce=1504970846", get_auth_default()).unwrap();
+        assert_eq!(None, data.get_channels());
+    }
+
     #[test]
     fn test_validate_time() {
         let data: HttpData = HttpData::new("/hello/world?nonce=1504970846", get_auth_default()).unwrap();
@@ -166,7 +248,13 @@ mod test {
     #[test]
     fn test_validate_token() {
         let data: HttpData = HttpData::new("/hello/world?nonce=1504970846", get_auth_default()).unwrap();
-        assert_eq!(true, data.validate_token("c3c3358c4fe308b198ee875597b16606f1c728aa", "1504970846"));
+        assert_eq!(true, data.validate_token("8ea8a92bf90a9c96549697c9173638405d780af9", "1504970846"));
+        assert_eq!(false, data.validate_token("c3c3358c4fe308b198ee875597b16606f1c728aa", "1504970846"));
+    }
+
+    #[test]
+    fn test_sign_token() {
+        assert_eq!("8ea8a92bf90a9c96549697c9173638405d780af9", sign_token("usocksecret", "1504970846"));
     }
 
     #[test]
@@ -184,10 +272,76 @@ mod test {
         auth.input(time.as_bytes());
 
         let data: HttpData = HttpData::new(
-            format!("/hello/world?nonce={}&token={}", time.as_str(), "ok").as_str(),
+            format!("/hello/world?nonce={}&token={}", time.as_str(), hex::encode(auth.result().code())).as_str(),
             get_auth_default(),
         ).unwrap();
 
         assert!(data.validate().is_none());
     }
+
+    #[test]
+    fn test_validate_errors() {
+        let time = Utc::now().timestamp();
+
+        let data: HttpData = HttpData::new("/hello/world?nonce=1", get_auth_default()).unwrap();
+        assert_eq!(Some(AuthError::MissingToken), data.validate());
+
+        let data: HttpData = HttpData::new("/hello/world?nonce=now&token=ok", get_auth_default()).unwrap();
+        assert_eq!(Some(AuthError::BadNonce("now".to_string())), data.validate());
+
+        let data: HttpData = HttpData::new("/hello/world?nonce=1504970846&token=ok", get_auth_default()).unwrap();
+        assert_eq!(Some(AuthError::Expired(1504970846)), data.validate());
+
+        let data: HttpData = HttpData::new(format!("/hello/world?nonce={}&token=ok", time).as_str(), get_auth_default()).unwrap();
+        assert_eq!(Some(AuthError::BadSignature), data.validate());
+    }
+
+    #[test]
+    fn test_validate_channels() {
+        let time = format!("{}", Utc::now().timestamp());
+        let token = sign_token("usocksecret", scope_public_key(time.as_str(), "news,hello/world").as_str());
+
+        let data: HttpData = HttpData::new(
+            format!("/hello/world?nonce={}&token={}&channels=news,hello/world", time.as_str(), token).as_str(),
+            get_auth_default(),
+        ).unwrap();
+        assert!(data.validate().is_none());
+
+        let data: HttpData = HttpData::new(
+            format!("/hello/world?nonce={}&token={}&channels=news", time.as_str(), token).as_str(),
+            get_auth_default(),
+        ).unwrap();
+        assert_eq!(Some(AuthError::ForbiddenChannel("hello/world".to_string())), data.validate());
+
+        let data: HttpData = HttpData::new(
+            format!("/news?nonce={}&token={}&channels=news", time.as_str(), token).as_str(),
+            get_auth_default(),
+        ).unwrap();
+        assert_eq!(Some(AuthError::BadSignature), data.validate());
+    }
+
+    #[test]
+    fn test_validate_user() {
+        let time = format!("{}", Utc::now().timestamp());
+        let token = sign_token("usocksecret", scope_user(scope_public_key(time.as_str(), "news").as_str(), "42").as_str());
+
+        let data: HttpData = HttpData::new(
+            format!("/news?nonce={}&token={}&channels=news&user=42", time.as_str(), token).as_str(),
+            get_auth_default(),
+        ).unwrap();
+        assert!(data.validate().is_none());
+        assert_eq!(Some("42".to_string()), data.get_user());
+
+        let data: HttpData = HttpData::new(
+            format!("/news?nonce={}&token={}&channels=news&user=43", time.as_str(), token).as_str(),
+            get_auth_default(),
+        ).unwrap();
+        assert_eq!(Some(AuthError::BadSignature), data.validate());
+
+        let data: HttpData = HttpData::new(
+            format!("/news?nonce={}&token={}&channels=news", time.as_str(), token).as_str(),
+            get_auth_default(),
+        ).unwrap();
+        assert_eq!(Some(AuthError::BadSignature), data.validate());
+    }
 }
\ No newline at end of file
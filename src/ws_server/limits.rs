@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ws::{CloseCode, Sender};
+
+use crate::settings::ws::WsServer;
+
+/// Admitted handshakes that never open are forgotten after this long.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub per_user: Option<usize>,
+    pub per_channel: Option<usize>,
+    pub evict_oldest: bool,
+}
+
+impl Limits {
+    pub fn from_settings(settings: &WsServer) -> Self {
+        Limits {
+            per_user: settings.get_max_connections_per_user(),
+            per_channel: settings.get_max_connections_per_channel(),
+            evict_oldest: settings.get_evict_oldest(),
+        }
+    }
+}
+
+/// Connection table shared by every handler of one websocket server.
+pub type SharedConnections = Arc<Mutex<Connections>>;
+
+struct Connection {
+    user: Option<String>,
+    group: String,
+    out: Option<Sender>,
+    admitted_at: Instant,
+    order: u64,
+}
+
+/// Connections admitted in `on_request` and opened in `on_open`, keyed on the
+/// server generated connection id.
+#[derive(Default)]
+pub struct Connections {
+    limits: Limits,
+    connections: HashMap<u32, Connection>,
+    next_order: u64,
+}
+
+impl Connections {
+    pub fn new(limits: Limits) -> Self {
+        Connections {
+            limits: limits,
+            ..Connections::default()
+        }
+    }
+
+    pub fn shared(limits: Limits) -> SharedConnections {
+        Arc::new(Mutex::new(Connections::new(limits)))
+    }
+
+    pub fn admit(&mut self, id: u32, user: Option<&str>, group: &str) -> Result<(), String> {
+        let now = Instant::now();
+        self.connections.retain(|_, c| c.out.is_some() || now.duration_since(c.admitted_at) < PENDING_TIMEOUT);
+
+        if let Some(max) = self.limits.per_channel {
+            self.make_room(max, |c| c.group == group)
+                .map_err(|count| format!("Channel [{}] already has {} connections", group, count))?;
+        }
+
+        if let (Some(max), Some(user)) = (self.limits.per_user, user) {
+            self.make_room(max, |c| c.user.as_ref().map(|u| u.as_str()) == Some(user))
+                .map_err(|count| format!("User [{}] already has {} connections", user, count))?;
+        }
+
+        self.next_order += 1;
+        self.connections.insert(id, Connection {
+            user: user.map(|u| u.to_string()),
+            group: group.to_string(),
+            out: None,
+            admitted_at: now,
+            order: self.next_order,
+        });
+
+        Ok(())
+    }
+
+    /// Ensures fewer than `max` connections match, closing the oldest open ones when
+    /// eviction is enabled. Returns the matching count when no room can be made.
+    fn make_room<F>(&mut self, max: usize, matches: F) -> Result<(), usize> where F: Fn(&Connection) -> bool {
+        let mut matching: Vec<(u64, u32)> = self.connections.iter()
+            .filter(|(_, c)| matches(c))
+            .map(|(id, c)| (c.order, *id))
+            .collect();
+
+        if matching.len() < max {
+            return Ok(());
+        }
+
+        if !self.limits.evict_oldest {
+            return Err(matching.len());
+        }
+
+        matching.sort();
+        let mut count = matching.len();
+
+        for (_, id) in matching {
+            if count < max {
+                break;
+            }
+
+            if let Some(out) = self.connections.get(&id).and_then(|c| c.out.clone()) {
+                if let Err(e) = out.close_with_reason(CloseCode::Policy, "Connection limit reached") {
+                    error!("{}", e);
+                }
+                self.connections.remove(&id);
+                count -= 1;
+            }
+        }
+
+        match count < max {
+            true => Ok(()),
+            false => Err(count),
+        }
+    }
+
+    pub fn open(&mut self, id: u32, out: Sender, group: &str) {
+        if let Some(connection) = self.connections.get_mut(&id) {
+            connection.out = Some(out);
+            return;
+        }
+
+        self.next_order += 1;
+        self.connections.insert(id, Connection {
+            user: None,
+            group: group.to_string(),
+            out: Some(out),
+            admitted_at: Instant::now(),
+            order: self.next_order,
+        });
+    }
+
+    pub fn close(&mut self, id: u32) {
+        self.connections.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ws::{Factory, Message, Sender, WebSocket};
+
+    use crate::ws_server::limits::{Connections, Limits};
+
+    /// An unstarted websocket, only used to hand out senders.
+    fn socket() -> WebSocket<impl Factory> {
+        WebSocket::new(|_: Sender| |_: Message| Ok(())).unwrap()
+    }
+
+    #[test]
+    fn test_admit_per_channel() {
+        let mut connections = Connections::new(Limits { per_channel: Some(2), ..Limits::default() });
+
+        assert!(connections.admit(1, None, "news").is_ok());
+        assert!(connections.admit(2, Some("alice"), "news").is_ok());
+        assert!(connections.admit(3, None, "news").is_err());
+        assert!(connections.admit(3, None, "sport").is_ok());
+
+        connections.close(1);
+        assert!(connections.admit(4, None, "news").is_ok());
+    }
+
+    #[test]
+    fn test_admit_per_user() {
+        let mut connections = Connections::new(Limits { per_user: Some(1), ..Limits::default() });
+
+        assert!(connections.admit(1, Some("alice"), "news").is_ok());
+        assert!(connections.admit(2, Some("alice"), "sport").is_err());
+        assert!(connections.admit(2, Some("bob"), "sport").is_ok());
+        assert!(connections.admit(3, None, "sport").is_ok());
+    }
+
+    #[test]
+    fn test_admit_evicts_only_open_connections() {
+        let mut connections = Connections::new(Limits { per_user: Some(1), evict_oldest: true, ..Limits::default() });
+
+        assert!(connections.admit(1, Some("alice"), "news").is_ok());
+        assert!(connections.admit(2, Some("alice"), "news").is_err());
+    }
+
+    #[test]
+    fn test_admit_evicts_oldest_open_connection() {
+        let socket = socket();
+        let mut connections = Connections::new(Limits { per_user: Some(2), evict_oldest: true, ..Limits::default() });
+
+        assert!(connections.admit(1, Some("alice"), "news").is_ok());
+        connections.open(1, socket.broadcaster(), "news");
+        assert!(connections.admit(2, Some("alice"), "sport").is_ok());
+        connections.open(2, socket.broadcaster(), "sport");
+
+        assert!(connections.admit(3, Some("alice"), "news").is_ok());
+        assert!(!connections.connections.contains_key(&1));
+        assert!(connections.connections.contains_key(&2));
+        assert!(connections.connections.contains_key(&3));
+    }
+}
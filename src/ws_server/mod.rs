@@ -7,18 +7,24 @@ use ws::{Builder, Sender, Settings};
 
 
 use crate::event::Event;
+use crate::session::SessionKey;
 use crate::settings::auth::Authorization;
+use crate::ws_server::limits::{Connections, Limits};
 
 mod server;
+pub mod limits;
 pub mod multicast;
 
-pub fn run_server(connect_str: &str, max_connections: usize, tx: ThreadSender<Event>, auth: Authorization) {
+pub fn run_server(connect_str: &str, max_connections: usize, tx: ThreadSender<Event>, auth: Authorization, limits: Limits) {
+    let session = SessionKey::from_auth(&auth);
+    let connections = Connections::shared(limits);
+
     Builder::new().with_settings(Settings {
         max_connections: max_connections,
         panic_on_internal: false,
         ..Settings::default()
     }).build(|out: Sender| {
-        server::Server::new(out, tx.clone(), auth.clone())
+        server::Server::new(out, tx.clone(), auth.clone(), session.clone(), connections.clone())
     }).unwrap().listen(connect_str).unwrap();
 }
 
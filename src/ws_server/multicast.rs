@@ -1,53 +1,184 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender as ThreadSender;
 
 use ws::Sender;
 
-use crate::event::Event;
+use crate::event::{Event, MultiCastMessage, RelayMessage};
 
-pub fn multicast(rx: Receiver<Event>, tx: ThreadSender<Event>) {
-    let mut rooms: HashMap<String, HashMap<String, Sender>> = HashMap::new();
+/// Where multicast delivers messages, the connection's websocket `Sender` outside of tests.
+pub trait Outbox {
+    fn deliver(&self, message: &str) -> ws::Result<()>;
+}
 
-    loop {
-        match rx.recv() {
-            Ok(Event::Subscribe((id, out, group))) => {
-                if let None = rooms.get_mut(group.as_str()) {
-                    rooms.insert(group.clone(), HashMap::new());
-                }
-                if let Some(room) = rooms.get_mut(group.as_str()) {
-                    room.insert(id, out);
-                }
+impl Outbox for Sender {
+    fn deliver(&self, message: &str) -> ws::Result<()> {
+        self.send(message)
+    }
+}
+
+/// Connections per channel, and the last snapshot of relayed channels. Channels the relay or
+/// candle threads publish into are owned by them, clients cannot post there.
+pub struct Rooms<O: Outbox> {
+    rooms: HashMap<String, HashMap<String, O>>,
+    snapshots: HashMap<String, String>,
+    owned: HashSet<String>,
+}
+
+impl<O: Outbox> Rooms<O> {
+    pub fn new(owned: HashSet<String>) -> Self {
+        Rooms {
+            rooms: HashMap::new(),
+            snapshots: HashMap::new(),
+            owned: owned,
+        }
+    }
+
+    /// Adds a connection to `group` and sends it the channel's snapshot, if any.
+    pub fn subscribe(&mut self, id: String, out: O, group: String) {
+        if let Some(snapshot) = self.snapshots.get(group.as_str()) {
+            if let Err(e) = out.deliver(snapshot.as_str()) {
+                error!("{}", e);
             }
-            Ok(Event::UnSubscribe((id, group))) => {
-                if let Some(room) = rooms.get_mut(group.as_str()) {
-                    room.remove(&id);
+        }
+
+        self.rooms.entry(group).or_insert_with(HashMap::new).insert(id, out);
+    }
+
+    pub fn unsubscribe(&mut self, id: &str, group: &str) {
+        if let Some(room) = self.rooms.get_mut(group) {
+            room.remove(id);
+        }
+    }
+
+    /// Delivers a client message to the other connections of its channel. Returns false if
+    /// the message was aimed at an owned channel and dropped.
+    pub fn multicast(&self, message: &MultiCastMessage) -> bool {
+        let channel = message.message.channel.as_str();
+
+        if self.owned.contains(channel) {
+            warn!("Dropped message from {} to relay channel [{}]", message.id, channel);
+            return false;
+        }
+
+        match self.rooms.get(channel) {
+            Some(room) => {
+                for (user, out) in room {
+                    if user != &message.id {
+                        if let Err(e) = out.deliver(message.message.message.as_str()) {
+                            error!("{}", e);
+                        }
+                    }
                 }
+                true
             }
-            Ok(Event::Multicast(message)) => {
-                if let Err(e) = tx.send(Event::Logging(message.message.clone())) {
+            _ => {
+                error!("Undefined room [{}]", channel);
+                true
+            }
+        }
+    }
+
+    pub fn relay(&mut self, message: RelayMessage) {
+        if let Some(room) = self.rooms.get(message.channel.as_str()) {
+            for out in room.values() {
+                if let Err(e) = out.deliver(message.message.as_str()) {
                     error!("{}", e);
                 }
+            }
+        }
+
+        if message.snapshot {
+            self.snapshots.insert(message.channel, message.message);
+        }
+    }
+}
 
-                let channel = message.message.channel.as_str();
+pub fn multicast(rx: Receiver<Event>, tx: ThreadSender<Event>, owned: HashSet<String>) {
+    let mut rooms: Rooms<Sender> = Rooms::new(owned);
 
-                match rooms.get(channel) {
-                    Some(room) => {
-                        for (user, out) in room {
-                            if user != &message.id {
-                                if let Err(e) = out.send(message.message.message.as_str()) {
-                                    error!("{}", e);
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        error!("Undefined room [{}]", message.message.channel.as_str());
+    loop {
+        match rx.recv() {
+            Ok(Event::Subscribe((id, out, group))) => rooms.subscribe(id, out, group),
+            Ok(Event::UnSubscribe((id, group))) => rooms.unsubscribe(id.as_str(), group.as_str()),
+            Ok(Event::Multicast(message)) => {
+                if rooms.multicast(&message) {
+                    if let Err(e) = tx.send(Event::Logging(message.message)) {
+                        error!("{}", e);
                     }
                 }
             }
+            Ok(Event::Relay(message)) => rooms.relay(message),
             Err(_) => panic!("MultiCast die"),
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    use crate::event::{MultiCastMessage, RelayMessage};
+    use crate::ws_server::multicast::{Outbox, Rooms};
+
+    impl Outbox for Sender<String> {
+        fn deliver(&self, message: &str) -> ws::Result<()> {
+            self.send(message.to_string()).map_err(|e| ws::Error::new(ws::ErrorKind::Internal, e.to_string()))
+        }
+    }
+
+    fn received(rx: &Receiver<String>) -> Vec<String> {
+        rx.try_iter().collect()
+    }
+
+    fn relay(channel: &str, message: &str, snapshot: bool) -> RelayMessage {
+        RelayMessage {
+            channel: channel.to_string(),
+            message: message.to_string(),
+            snapshot: snapshot,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_on_join() {
+        let mut rooms: Rooms<Sender<String>> = Rooms::new(HashSet::new());
+        let (early, early_rx) = channel();
+        rooms.subscribe("1".to_string(), early, "ticker/btc-usdt".to_string());
+
+        rooms.relay(relay("ticker/btc-usdt", "first", true));
+        rooms.relay(relay("ticker/btc-usdt", "trade", false));
+        rooms.relay(relay("ticker/btc-usdt", "second", true));
+        assert_eq!(vec!["first", "trade", "second"], received(&early_rx));
+
+        let (late, late_rx) = channel();
+        rooms.subscribe("2".to_string(), late, "ticker/btc-usdt".to_string());
+        assert_eq!(vec!["second"], received(&late_rx));
+
+        let (other, other_rx) = channel();
+        rooms.subscribe("3".to_string(), other, "news".to_string());
+        assert!(received(&other_rx).is_empty());
+    }
+
+    #[test]
+    fn test_multicast_skips_owned_channels() {
+        let owned: HashSet<String> = vec!["ticker/btc-usdt".to_string()].into_iter().collect();
+        let mut rooms: Rooms<Sender<String>> = Rooms::new(owned);
+        let (first, first_rx) = channel();
+        let (second, second_rx) = channel();
+        let (third, third_rx) = channel();
+        rooms.subscribe("1".to_string(), first, "ticker/btc-usdt".to_string());
+        rooms.subscribe("2".to_string(), second, "news".to_string());
+        rooms.subscribe("3".to_string(), third, "news".to_string());
+
+        let spoofed = MultiCastMessage::new("ticker/btc-usdt".to_string(), "9".to_string(), "fake".to_string(), "127.0.0.1".to_string());
+        assert!(!rooms.multicast(&spoofed));
+        assert!(received(&first_rx).is_empty());
+
+        let chat = MultiCastMessage::new("news".to_string(), "2".to_string(), "hello".to_string(), "127.0.0.1".to_string());
+        assert!(rooms.multicast(&chat));
+        assert!(received(&second_rx).is_empty());
+        assert_eq!(vec!["hello"], received(&third_rx));
+    }
+}
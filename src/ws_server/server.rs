@@ -3,9 +3,12 @@ use std::sync::mpsc::Sender as ThreadSender;
 use ws::{CloseCode,  Handler, Handshake, Message, Request, Response, Result, Sender};
 
 
+use crate::auth::AuthError;
 use crate::event::{Event, MultiCastMessage};
+use crate::session::SessionKey;
 use crate::settings::auth::Authorization;
-use crate::utils::HttpData;
+use crate::utils::{normalize_group, HttpData};
+use crate::ws_server::limits::SharedConnections;
 
 pub struct Server {
     out: Sender,
@@ -13,20 +16,88 @@ pub struct Server {
     id: String,
     group: String,
     auth: Authorization,
+    session: Option<SessionKey>,
+    connections: SharedConnections,
+    user_id: Option<String>,
     ip: String,
 }
 
 impl Server {
-    pub fn new(out: Sender, extern_out: ThreadSender<Event>, auth: Authorization) -> Self {
+    pub fn new(out: Sender, extern_out: ThreadSender<Event>, auth: Authorization, session: Option<SessionKey>, connections: SharedConnections) -> Self {
         Server {
+            id: format!("{}", out.connection_id()),
             out: out,
             extern_out: extern_out,
-            id: "".to_string(),
             group: "".to_string(),
             auth: auth,
+            session: session,
+            connections: connections,
+            user_id: None,
             ip: "127.0.0.1".to_string(),
         }
     }
+
+    fn get_cookie_header(&self, req: &Request) -> Option<String> {
+        req.header("Cookie").and_then(|header| String::from_utf8(header.clone()).ok())
+    }
+
+    /// Users logged in through the HTTP API carry Rocket's private `user_id` cookie.
+    fn get_session_user_id(&self, req: &Request) -> Option<String> {
+        let session = self.session.as_ref()?;
+        session.get_user_id(self.get_cookie_header(req)?.as_str())
+    }
+
+    /// Cookie users may only join the channels in their private `channels` cookie.
+    fn validate_session_channel(&self, req: &Request, group: &str) -> Option<AuthError> {
+        let channels = match (self.session.as_ref(), self.get_cookie_header(req)) {
+            (Some(session), Some(header)) => session.get_channels(header.as_str()),
+            _ => vec![]
+        };
+
+        match channels.iter().any(|channel| normalize_group(channel) == group) {
+            true => None,
+            false => Some(AuthError::ForbiddenChannel(group.to_string())),
+        }
+    }
+
+    /// Handshakes carrying a session cookie must come from an allowed origin. Token requests
+    /// without an `Origin` header come from non-browser clients and are let through.
+    fn validate_origin(&self, req: &Request, with_cookie: bool) -> Option<AuthError> {
+        let allowed_origins = match self.auth.get_allowed_origins() {
+            Some(allowed_origins) => allowed_origins,
+            None if with_cookie => return Some(AuthError::OriginDenied("".to_string())),
+            None => return None,
+        };
+
+        match req.origin() {
+            Ok(Some(origin)) if allowed_origins.iter().any(|allowed| allowed == origin) => None,
+            Ok(Some(origin)) => Some(AuthError::OriginDenied(origin.to_string())),
+            Ok(None) if with_cookie => Some(AuthError::OriginDenied("".to_string())),
+            Ok(None) => None,
+            Err(_) => Some(AuthError::OriginDenied("".to_string())),
+        }
+    }
+
+    /// Checks the connection limits before upgrading. Fails closed if the table is poisoned.
+    fn admit(&self) -> Option<AuthError> {
+        let mut connections = match self.connections.lock() {
+            Ok(connections) => connections,
+            Err(e) => {
+                error!("Connection table unavailable: {}", e);
+                return Some(AuthError::TooManyConnections("Connection limits unavailable".to_string()));
+            }
+        };
+
+        match connections.admit(self.out.connection_id(), self.user_id.as_ref().map(|u| u.as_str()), self.group.as_str()) {
+            Ok(()) => None,
+            Err(reason) => Some(AuthError::TooManyConnections(reason)),
+        }
+    }
+
+    fn reject(&self, req: &Request, e: AuthError) -> Result<Response> {
+        warn!("Handshake for {} rejected: {}", req.resource(), e);
+        Ok(e.to_response())
+    }
 }
 
 impl Handler for Server {
@@ -35,6 +106,11 @@ impl Handler for Server {
             self.ip = ip_addr.to_string()
         }
 
+        match self.connections.lock() {
+            Ok(mut connections) => connections.open(self.out.connection_id(), self.out.clone(), self.group.as_str()),
+            Err(e) => error!("{}", e)
+        }
+
         if let Err(e) = self.extern_out.send(Event::Subscribe((self.id.clone(), self.out.clone(), self.group.clone()))) {
             error!("{}", e)
         }
@@ -52,6 +128,11 @@ impl Handler for Server {
     }
 
     fn on_close(&mut self, _: CloseCode, _: &str) {
+        match self.connections.lock() {
+            Ok(mut connections) => connections.close(self.out.connection_id()),
+            Err(e) => error!("{}", e)
+        }
+
         if let Err(e) = self.extern_out.send(Event::UnSubscribe((self.id.clone(), self.group.clone()))) {
             error!("{}", e)
         }
@@ -63,14 +144,29 @@ impl Handler for Server {
             self.auth.clone(),
         )?;
 
-        if let Some(e) = uri.validate() {
-            return Err(e);
+        self.user_id = self.get_session_user_id(req);
+        self.group = uri.get_group();
+
+        if let Some(e) = self.validate_origin(req, self.user_id.is_some()) {
+            return self.reject(req, e);
         }
 
-        self.group = uri.get_group();
+        let denied = match self.user_id {
+            Some(_) => self.validate_session_channel(req, self.group.as_str()),
+            None => uri.validate(),
+        };
+
+        if let Some(e) = denied {
+            return self.reject(req, e);
+        }
+
+        // Tokens may be bound to a user, which puts token connections under the per-user limit too
+        if self.user_id.is_none() {
+            self.user_id = uri.get_user();
+        }
 
-        if let Some(Ok(id)) = req.header("Sec-WebSocket-Key").map(|id| String::from_utf8(id.clone())) {
-            self.id = id;
+        if let Some(e) = self.admit() {
+            return self.reject(req, e);
         }
 
         Response::from_request(req)